@@ -0,0 +1,552 @@
+use std::str::FromStr;
+
+use anyhow::{Context, Result, anyhow};
+
+use crate::controller::{CR, REPLY_IDX, STX};
+use crate::num_to_bytes;
+
+/// Encodes `value` as ASCII digits in `radix` (2 to 36, upper-case letters above 9), zero-padded
+/// to at least `width` digits.
+///
+/// Negative values get a leading `-` which does not count towards `width`, so `-5` at width 3 is
+/// `-005`. A `width` of 0 or 1 gives the shortest form, `0` for zero.
+pub fn encode_int(value: isize, radix: u32, width: usize) -> Result<Vec<u8>> {
+    if !(2..=36).contains(&radix) {
+        return Err(anyhow!("unsupported radix {radix}"));
+    }
+    let mut magnitude = value.unsigned_abs();
+    let mut digits = Vec::new();
+    loop {
+        let digit = (magnitude % radix as usize) as u32;
+        digits.push(
+            char::from_digit(digit, radix)
+                .expect("digit below radix")
+                .to_ascii_uppercase() as u8,
+        );
+        magnitude /= radix as usize;
+        if magnitude == 0 {
+            break;
+        }
+    }
+    digits.resize(digits.len().max(width), b'0');
+    if value < 0 {
+        digits.push(b'-');
+    }
+    digits.reverse();
+    Ok(digits)
+}
+
+/// Builds the frame `prefix, mnemonic, [decimal argument], CR`, where `prefix` is
+/// `STX, device, id` as made by `make_prefix`.
+pub fn encode_command(prefix: &[u8], mnemonic: &[u8; 2], arg: Option<isize>) -> Vec<u8> {
+    let arg = arg.map(num_to_bytes).unwrap_or_default();
+    let mut msg = Vec::with_capacity(prefix.len() + mnemonic.len() + arg.len() + 1);
+    msg.extend_from_slice(prefix);
+    msg.extend_from_slice(mnemonic);
+    msg.extend_from_slice(&arg);
+    msg.push(CR);
+    msg
+}
+
+pub fn encode_enable(prefix: &[u8]) -> Vec<u8> {
+    encode_command(prefix, b"EN", None)
+}
+
+pub fn encode_disable(prefix: &[u8]) -> Vec<u8> {
+    encode_command(prefix, b"DE", None)
+}
+
+pub fn encode_absolute_move(prefix: &[u8], counts: isize) -> Vec<u8> {
+    encode_command(prefix, b"AM", Some(counts))
+}
+
+pub fn encode_relative_move(prefix: &[u8], counts: isize) -> Vec<u8> {
+    encode_command(prefix, b"RM", Some(counts))
+}
+
+pub fn encode_jog(prefix: &[u8], counts_per_sec: isize) -> Vec<u8> {
+    encode_command(prefix, b"JG", Some(counts_per_sec))
+}
+
+pub fn encode_abrupt_stop(prefix: &[u8]) -> Vec<u8> {
+    encode_command(prefix, b"AS", None)
+}
+
+pub fn encode_stop(prefix: &[u8]) -> Vec<u8> {
+    encode_command(prefix, b"ST", None)
+}
+
+pub fn encode_set_position(prefix: &[u8], counts: isize) -> Vec<u8> {
+    encode_command(prefix, b"SP", Some(counts))
+}
+
+pub fn encode_set_velocity(prefix: &[u8], counts_per_sec: isize) -> Vec<u8> {
+    encode_command(prefix, b"SV", Some(counts_per_sec))
+}
+
+pub fn encode_set_acceleration(prefix: &[u8], counts_per_sec2: isize) -> Vec<u8> {
+    encode_command(prefix, b"SA", Some(counts_per_sec2))
+}
+
+pub fn encode_set_deceleration(prefix: &[u8], counts_per_sec2: isize) -> Vec<u8> {
+    encode_command(prefix, b"SD", Some(counts_per_sec2))
+}
+
+pub fn encode_set_torque_limit(prefix: &[u8], percent: isize) -> Vec<u8> {
+    encode_command(prefix, b"TL", Some(percent))
+}
+
+pub fn encode_get_status(prefix: &[u8]) -> Vec<u8> {
+    encode_command(prefix, b"GS", None)
+}
+
+pub fn encode_get_position(prefix: &[u8]) -> Vec<u8> {
+    encode_command(prefix, b"GP", None)
+}
+
+pub fn encode_get_velocity(prefix: &[u8]) -> Vec<u8> {
+    encode_command(prefix, b"GV", None)
+}
+
+pub fn encode_get_acceleration(prefix: &[u8]) -> Vec<u8> {
+    encode_command(prefix, b"GA", None)
+}
+
+pub fn encode_get_torque(prefix: &[u8]) -> Vec<u8> {
+    encode_command(prefix, b"GT", None)
+}
+
+pub fn encode_get_alerts(prefix: &[u8]) -> Vec<u8> {
+    encode_command(prefix, b"AL", None)
+}
+
+pub fn encode_home(prefix: &[u8]) -> Vec<u8> {
+    encode_command(prefix, b"HM", None)
+}
+
+pub fn encode_clear_alerts(prefix: &[u8]) -> Vec<u8> {
+    encode_command(prefix, b"CA", None)
+}
+
+/// Asks the controller (`C0`) for its device name.
+pub fn encode_get_identity(prefix: &[u8]) -> Vec<u8> {
+    encode_command(prefix, b"ID", None)
+}
+
+pub fn encode_get_firmware_version(prefix: &[u8]) -> Vec<u8> {
+    encode_command(prefix, b"FV", None)
+}
+
+pub fn encode_get_power_status(prefix: &[u8]) -> Vec<u8> {
+    encode_command(prefix, b"PW", None)
+}
+
+/// Reads an input: the bare `STX, b'I', id` prefix and CR.
+pub fn encode_read_input(prefix: &[u8]) -> Vec<u8> {
+    let mut msg = prefix.to_vec();
+    msg.push(CR);
+    msg
+}
+
+/// Sets the controller's filter on a digital input, in 200 µs samples.
+pub fn encode_set_input_filter(prefix: &[u8], samples: isize) -> Vec<u8> {
+    encode_command(prefix, b"SF", Some(samples))
+}
+
+pub fn encode_get_input_filter(prefix: &[u8]) -> Vec<u8> {
+    encode_command(prefix, b"GF", None)
+}
+
+pub fn encode_get_adc_range(prefix: &[u8]) -> Vec<u8> {
+    encode_command(prefix, b"AR", None)
+}
+
+/// Drives an output or h-bridge: the value follows the `STX, b'O', id` prefix directly.
+pub fn encode_set_output(prefix: &[u8], value: isize) -> Vec<u8> {
+    let value = num_to_bytes(value);
+    let mut msg = Vec::with_capacity(prefix.len() + value.len() + 1);
+    msg.extend_from_slice(prefix);
+    msg.extend_from_slice(&value);
+    msg.push(CR);
+    msg
+}
+
+/// How the fields of a multi-field reply are laid out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout<'a> {
+    /// Fields separated by this byte, e.g. `b','`.
+    Delimited(u8),
+    /// Fields of these fixed widths, back to back.
+    Fixed(&'a [usize]),
+}
+
+/// Payload of a reply: everything after the `STX, device, id` prefix up to the CR terminator
+/// or zero padding.
+pub fn reply_payload(reply: &[u8]) -> &[u8] {
+    let payload = reply.get(REPLY_IDX..).unwrap_or_default();
+    let end = payload
+        .iter()
+        .position(|&b| b == CR || b == 0)
+        .unwrap_or(payload.len());
+    &payload[..end]
+}
+
+/// Splits the payload of `reply` into its fields, surrounding spaces trimmed. A fixed layout
+/// errors if the payload is shorter than the widths add up to.
+pub fn split_fields<'a>(reply: &'a [u8], layout: Layout<'_>) -> Result<Vec<&'a [u8]>> {
+    let payload = reply_payload(reply);
+    let fields = match layout {
+        Layout::Delimited(delimiter) => payload.split(|&b| b == delimiter).collect(),
+        Layout::Fixed(widths) => {
+            let mut rest = payload;
+            let mut fields = Vec::with_capacity(widths.len());
+            for &width in widths {
+                if rest.len() < width {
+                    return Err(anyhow!(
+                        "reply {:?} too short for field widths {widths:?}",
+                        String::from_utf8_lossy(payload)
+                    ));
+                }
+                let (field, tail) = rest.split_at(width);
+                fields.push(field);
+                rest = tail;
+            }
+            fields
+        }
+    };
+    Ok(fields.into_iter().map(<[u8]>::trim_ascii).collect())
+}
+
+/// Splits `reply` like `split_fields` and parses each field; an empty field is `None`.
+pub fn parse_fields<T>(reply: &[u8], layout: Layout<'_>) -> Result<Vec<Option<T>>>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    split_fields(reply, layout)?
+        .into_iter()
+        .enumerate()
+        .map(|(idx, field)| {
+            if field.is_empty() {
+                return Ok(None);
+            }
+            let text = std::str::from_utf8(field)?;
+            text.parse()
+                .map(Some)
+                .with_context(|| format!("field {idx} ({text:?})"))
+        })
+        .collect()
+}
+
+/// Human-readable form of a command frame for logs, e.g. `M0 AM 8000` for an absolute move of
+/// motor 0. Frames that don't start with STX come back as their raw bytes.
+pub fn describe_frame(frame: &[u8]) -> String {
+    let frame = match frame.iter().position(|&b| b == CR) {
+        Some(end) => &frame[..end],
+        None => frame,
+    };
+    match frame {
+        [STX, device, id, rest @ ..] if device.is_ascii_alphabetic() && *id >= b'0' => {
+            let (mnemonic, arg) = rest.split_at(rest.len().min(2));
+            let mut text = format!(
+                "{}{} {}",
+                *device as char,
+                id - b'0',
+                String::from_utf8_lossy(mnemonic)
+            );
+            if !arg.is_empty() {
+                text.push(' ');
+                text.push_str(&String::from_utf8_lossy(arg));
+            }
+            text
+        }
+        _ => format!("{frame:?}"),
+    }
+}
+
+/// Broad category of a command frame, used to pick its reply timeout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CommandKind {
+    /// Reads state without changing it.
+    Query,
+    /// Changes a setting or an output.
+    Config,
+    /// Starts motion.
+    Motion,
+    Enable,
+    Stop,
+}
+
+impl CommandKind {
+    /// Classifies a frame of the form `STX, device, id, mnemonic..., CR`.
+    pub fn of(frame: &[u8]) -> Self {
+        let device = frame.get(1).copied();
+        let mnemonic = frame.get(3..5).unwrap_or_default();
+        match (device, mnemonic) {
+            (Some(b'M'), b"EN") => CommandKind::Enable,
+            (Some(b'M'), b"ST" | b"AS") => CommandKind::Stop,
+            (Some(b'M'), b"AM" | b"RM" | b"JG" | b"HM") => CommandKind::Motion,
+            (Some(b'M'), b"AL" | b"VD") => CommandKind::Query,
+            (Some(b'M' | b'I' | b'O'), [b'G', _]) => CommandKind::Query,
+            (Some(b'I'), [b'S', _]) => CommandKind::Config,
+            (Some(b'I' | b'C'), _) => CommandKind::Query,
+            _ => CommandKind::Config,
+        }
+    }
+}
+
+/// Motor commands of the stock ClearCore protocol, together with input reads (`I<id>`) and
+/// output values (`O<id><value>`): enable, disable, absolute and relative moves, jog, abrupt
+/// and ramped stop, set position, velocity, acceleration and deceleration, status, position
+/// and clearing alerts.
+const BASELINE_MOTOR_COMMANDS: [&[u8; 2]; 14] = [
+    b"EN", b"DE", b"AM", b"RM", b"JG", b"AS", b"ST", b"SP", b"SV", b"SA", b"SD", b"GS", b"GP",
+    b"CA",
+];
+
+/// Commands this crate sends beyond the stock protocol, which only firmware built with them
+/// answers, by device and mnemonic.
+const EXTENSION_COMMANDS: [(u8, &[u8; 2], &str); 19] = [
+    (b'C', b"ID", "identity query (C0ID)"),
+    (b'C', b"FV", "firmware version query (C0FV)"),
+    (b'C', b"PW", "power status query (C0PW)"),
+    (b'M', b"GV", "velocity limit query (GV)"),
+    (b'M', b"GA", "acceleration limit query (GA)"),
+    (b'M', b"GT", "torque query (GT)"),
+    (b'M', b"GH", "HLFB query (GH)"),
+    (b'M', b"GM", "HLFB mode query (GM)"),
+    (b'M', b"SM", "HLFB mode change (SM)"),
+    (b'M', b"VD", "velocity detail query (VD)"),
+    (b'M', b"AL", "alert register query (AL)"),
+    (b'M', b"TL", "torque limit (TL)"),
+    (b'M', b"HM", "firmware homing (HM)"),
+    (b'I', b"SF", "input filter (SF)"),
+    (b'I', b"GF", "input filter query (GF)"),
+    (b'I', b"AR", "ADC range query (AR)"),
+    (b'O', b"GO", "output query (GO)"),
+    (b'O', b"GS", "h-bridge status query (GS)"),
+    (b'O', b"BK", "h-bridge brake (BK)"),
+];
+
+/// What `frame` asks for if it is not part of the stock protocol, e.g. `torque query (GT)`;
+/// `None` for the stock commands. A firmware without an extension rejects it with
+/// `ControllerErrorCode::UnknownCommand`, which `SendRecv::write` turns into
+/// `ClearCoreError::Unsupported` naming the extension.
+pub fn extension(frame: &[u8]) -> Option<&'static str> {
+    let device = *frame.get(1)?;
+    let mnemonic = frame.get(3..5).unwrap_or_default();
+    let baseline = match device {
+        b'M' => BASELINE_MOTOR_COMMANDS
+            .iter()
+            .any(|command| command[..] == *mnemonic),
+        b'I' => frame.get(3) == Some(&CR),
+        b'O' => frame
+            .get(3)
+            .is_some_and(|first| *first == b'-' || first.is_ascii_digit()),
+        _ => false,
+    };
+    (!baseline).then(|| {
+        EXTENSION_COMMANDS
+            .iter()
+            .find(|(d, m, _)| *d == device && m[..] == *mnemonic)
+            .map_or("extension command", |(_, _, name)| *name)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOTOR_0: [u8; 3] = [STX, b'M', b'0'];
+    const INPUT_1: [u8; 3] = [STX, b'I', b'1'];
+    const OUTPUT_4: [u8; 3] = [STX, b'O', b'4'];
+    const CONTROLLER: [u8; 3] = [STX, b'C', b'0'];
+
+    #[test]
+    fn golden_motor_commands() {
+        let cases: &[(Vec<u8>, &[u8])] = &[
+            (encode_enable(&MOTOR_0), b"\x02M0EN\r"),
+            (encode_disable(&MOTOR_0), b"\x02M0DE\r"),
+            (encode_absolute_move(&MOTOR_0, 8000), b"\x02M0AM8000\r"),
+            (encode_absolute_move(&MOTOR_0, -800), b"\x02M0AM-800\r"),
+            (encode_relative_move(&MOTOR_0, -1600), b"\x02M0RM-1600\r"),
+            (encode_jog(&MOTOR_0, 400), b"\x02M0JG400\r"),
+            (encode_abrupt_stop(&MOTOR_0), b"\x02M0AS\r"),
+            (encode_stop(&MOTOR_0), b"\x02M0ST\r"),
+            (encode_set_position(&MOTOR_0, 0), b"\x02M0SP0\r"),
+            (encode_set_velocity(&MOTOR_0, 16000), b"\x02M0SV16000\r"),
+            (encode_set_acceleration(&MOTOR_0, 80000), b"\x02M0SA80000\r"),
+            (encode_set_deceleration(&MOTOR_0, 40000), b"\x02M0SD40000\r"),
+            (encode_set_torque_limit(&MOTOR_0, 50), b"\x02M0TL50\r"),
+            (encode_get_status(&MOTOR_0), b"\x02M0GS\r"),
+            (encode_get_position(&MOTOR_0), b"\x02M0GP\r"),
+            (encode_clear_alerts(&MOTOR_0), b"\x02M0CA\r"),
+            (encode_get_velocity(&MOTOR_0), b"\x02M0GV\r"),
+            (encode_get_acceleration(&MOTOR_0), b"\x02M0GA\r"),
+            (encode_home(&MOTOR_0), b"\x02M0HM\r"),
+            (encode_get_alerts(&MOTOR_0), b"\x02M0AL\r"),
+            (encode_get_torque(&MOTOR_0), b"\x02M0GT\r"),
+        ];
+        for (encoded, golden) in cases {
+            assert_eq!(encoded, golden, "{}", describe_frame(golden));
+        }
+    }
+
+    #[test]
+    fn golden_controller_commands() {
+        assert_eq!(encode_get_identity(&CONTROLLER), b"\x02C0ID\r");
+        assert_eq!(encode_get_firmware_version(&CONTROLLER), b"\x02C0FV\r");
+        assert_eq!(encode_get_power_status(&CONTROLLER), b"\x02C0PW\r");
+    }
+
+    /// One golden frame per `EXTENSION_COMMANDS` entry, so an extension cannot be added to the
+    /// table without a test pinning its encoding.
+    #[test]
+    fn golden_extension_commands() {
+        let cases: &[(Vec<u8>, &[u8])] = &[
+            (encode_get_identity(&CONTROLLER), b"\x02C0ID\r"),
+            (encode_get_firmware_version(&CONTROLLER), b"\x02C0FV\r"),
+            (encode_get_power_status(&CONTROLLER), b"\x02C0PW\r"),
+            (encode_get_velocity(&MOTOR_0), b"\x02M0GV\r"),
+            (encode_get_acceleration(&MOTOR_0), b"\x02M0GA\r"),
+            (encode_get_torque(&MOTOR_0), b"\x02M0GT\r"),
+            (encode_command(&MOTOR_0, b"GH", None), b"\x02M0GH\r"),
+            (encode_command(&MOTOR_0, b"GM", None), b"\x02M0GM\r"),
+            (encode_command(&MOTOR_0, b"SM", Some(2)), b"\x02M0SM2\r"),
+            (encode_command(&MOTOR_0, b"VD", None), b"\x02M0VD\r"),
+            (encode_get_alerts(&MOTOR_0), b"\x02M0AL\r"),
+            (encode_set_torque_limit(&MOTOR_0, 50), b"\x02M0TL50\r"),
+            (encode_home(&MOTOR_0), b"\x02M0HM\r"),
+            (encode_set_input_filter(&INPUT_1, 25), b"\x02I1SF25\r"),
+            (encode_get_input_filter(&INPUT_1), b"\x02I1GF\r"),
+            (encode_get_adc_range(&INPUT_1), b"\x02I1AR\r"),
+            (encode_command(&OUTPUT_4, b"GO", None), b"\x02O4GO\r"),
+            (encode_command(&OUTPUT_4, b"GS", None), b"\x02O4GS\r"),
+            (encode_command(&OUTPUT_4, b"BK", None), b"\x02O4BK\r"),
+        ];
+        for (encoded, golden) in cases {
+            assert_eq!(encoded, golden, "{}", describe_frame(golden));
+        }
+        for (_, _, name) in EXTENSION_COMMANDS {
+            assert!(
+                cases
+                    .iter()
+                    .any(|(_, golden)| extension(golden) == Some(name)),
+                "no golden frame for {name}"
+            );
+        }
+    }
+
+    #[test]
+    fn golden_absolute_move_bytes() {
+        assert_eq!(
+            encode_absolute_move(&MOTOR_0, 8000),
+            [2, b'M', b'0', b'A', b'M', b'8', b'0', b'0', b'0', 13]
+        );
+    }
+
+    #[test]
+    fn golden_io_commands() {
+        assert_eq!(encode_read_input(&INPUT_1), b"\x02I1\r");
+        assert_eq!(encode_set_input_filter(&INPUT_1, 25), b"\x02I1SF25\r");
+        assert_eq!(encode_set_output(&OUTPUT_4, 32700), b"\x02O432700\r");
+        assert_eq!(encode_set_output(&OUTPUT_4, 0), b"\x02O40\r");
+        assert_eq!(encode_set_output(&OUTPUT_4, -16000), b"\x02O4-16000\r");
+        assert_eq!(encode_command(&OUTPUT_4, b"BK", None), b"\x02O4BK\r");
+        assert_eq!(encode_command(&OUTPUT_4, b"GO", None), b"\x02O4GO\r");
+        assert_eq!(encode_command(&OUTPUT_4, b"GS", None), b"\x02O4GS\r");
+    }
+
+    #[test]
+    fn encode_int_negative() {
+        assert_eq!(encode_int(-5, 10, 0).unwrap(), b"-5");
+        assert_eq!(encode_int(-5, 10, 4).unwrap(), b"-0005");
+        assert_eq!(encode_int(-26, 16, 0).unwrap(), b"-1A");
+        assert_eq!(
+            encode_int(isize::MIN, 10, 0).unwrap(),
+            isize::MIN.to_string().as_bytes()
+        );
+    }
+
+    #[test]
+    fn encode_int_zero() {
+        assert_eq!(encode_int(0, 10, 0).unwrap(), b"0");
+        assert_eq!(encode_int(0, 10, 1).unwrap(), b"0");
+        assert_eq!(encode_int(0, 10, 3).unwrap(), b"000");
+    }
+
+    #[test]
+    fn encode_int_width_and_radix() {
+        assert_eq!(encode_int(42, 10, 4).unwrap(), b"0042");
+        assert_eq!(encode_int(12345, 10, 3).unwrap(), b"12345");
+        assert_eq!(encode_int(255, 16, 0).unwrap(), b"FF");
+        assert_eq!(encode_int(255, 16, 4).unwrap(), b"00FF");
+        assert_eq!(encode_int(5, 2, 8).unwrap(), b"00000101");
+        assert!(encode_int(1, 1, 0).is_err());
+        assert!(encode_int(1, 37, 0).is_err());
+    }
+
+    #[test]
+    fn stock_commands_are_not_extensions() {
+        for frame in [
+            encode_absolute_move(&MOTOR_0, 100),
+            encode_get_position(&MOTOR_0),
+            encode_read_input(&INPUT_1),
+            encode_set_output(&OUTPUT_4, -5),
+        ] {
+            assert_eq!(extension(&frame), None, "{}", describe_frame(&frame));
+        }
+        assert_eq!(
+            extension(&encode_set_torque_limit(&MOTOR_0, 50)),
+            Some("torque limit (TL)")
+        );
+        assert_eq!(
+            extension(&encode_set_input_filter(&INPUT_1, 25)),
+            Some("input filter (SF)")
+        );
+        assert_eq!(extension(b"\x02C0PW\r"), Some("power status query (C0PW)"));
+        assert_eq!(extension(b"\x02M0ZZ\r"), Some("extension command"));
+    }
+
+    #[test]
+    fn split_delimited_fields() {
+        let reply = b"\x02M01200, -40\r\0\0";
+        assert_eq!(
+            split_fields(reply, Layout::Delimited(b',')).unwrap(),
+            [&b"1200"[..], b"-40"]
+        );
+    }
+
+    #[test]
+    fn split_fixed_fields() {
+        let reply = b"\x02C0 124000 1\r";
+        assert_eq!(
+            split_fields(reply, Layout::Fixed(&[7, 2])).unwrap(),
+            [&b"124000"[..], b"1"]
+        );
+        assert!(split_fields(reply, Layout::Fixed(&[7, 3])).is_err());
+    }
+
+    #[test]
+    fn empty_fields_parse_as_none() {
+        assert_eq!(
+            parse_fields::<f64>(b"\x02C0,1\r", Layout::Delimited(b',')).unwrap(),
+            [None, Some(1.)]
+        );
+        assert_eq!(
+            parse_fields::<f64>(b"\x02C0\r", Layout::Delimited(b',')).unwrap(),
+            [None]
+        );
+        assert_eq!(
+            parse_fields::<isize>(b"\x02M0  7\r", Layout::Fixed(&[2, 1])).unwrap(),
+            [None, Some(7)]
+        );
+    }
+
+    #[test]
+    fn malformed_fields_name_the_field() {
+        let error = parse_fields::<isize>(b"\x02M012,x4\r", Layout::Delimited(b','))
+            .expect_err("malformed field parsed");
+        assert_eq!(error.to_string(), "field 1 (\"x4\")");
+    }
+}
@@ -0,0 +1,208 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use tokio::time::MissedTickBehavior;
+
+use crate::controller::ControllerHandle;
+use crate::error::ClearCoreError;
+use crate::join_all;
+use crate::motor::Status;
+
+const DEFAULT_POLL: Duration = Duration::from_millis(50);
+
+/// Predicate over controller state for `ControllerHandle::wait_for`, built from the leaf
+/// constructors (`motor_ready`, `input_high`, ...) and combined with `all`, `any` and `!`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Condition {
+    MotorStatus {
+        motor: usize,
+        status: Status,
+    },
+    /// Digital input `input` reads high (`true`) or low.
+    Input {
+        input: usize,
+        high: bool,
+    },
+    All(Vec<Condition>),
+    Any(Vec<Condition>),
+    Not(Box<Condition>),
+}
+
+pub fn motor_status(motor: usize, status: Status) -> Condition {
+    Condition::MotorStatus { motor, status }
+}
+
+pub fn motor_ready(motor: usize) -> Condition {
+    motor_status(motor, Status::Ready)
+}
+
+pub fn input_high(input: usize) -> Condition {
+    Condition::Input { input, high: true }
+}
+
+pub fn input_low(input: usize) -> Condition {
+    Condition::Input { input, high: false }
+}
+
+/// A device read a condition depends on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Read {
+    Motor(usize),
+    Input(usize),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Value {
+    Status(Status),
+    Level(bool),
+}
+
+impl Condition {
+    /// Holds when every condition does; an empty list always holds.
+    pub fn all(conditions: impl IntoIterator<Item = Condition>) -> Self {
+        Condition::All(conditions.into_iter().collect())
+    }
+
+    /// Holds when at least one condition does; an empty list never holds.
+    pub fn any(conditions: impl IntoIterator<Item = Condition>) -> Self {
+        Condition::Any(conditions.into_iter().collect())
+    }
+
+    /// Gives up after `timeout`, see `Wait`.
+    pub fn timeout(self, timeout: Duration) -> Wait {
+        Wait::from(self).timeout(timeout)
+    }
+
+    fn reads(&self, reads: &mut Vec<Read>) {
+        let read = match self {
+            Condition::MotorStatus { motor, .. } => Read::Motor(*motor),
+            Condition::Input { input, .. } => Read::Input(*input),
+            Condition::All(conditions) | Condition::Any(conditions) => {
+                for condition in conditions {
+                    condition.reads(reads);
+                }
+                return;
+            }
+            Condition::Not(condition) => return condition.reads(reads),
+        };
+        if !reads.contains(&read) {
+            reads.push(read);
+        }
+    }
+
+    fn holds(&self, values: &[(Read, Value)]) -> bool {
+        let value = |read| {
+            values
+                .iter()
+                .find(|(r, _)| *r == read)
+                .map(|(_, value)| *value)
+        };
+        match self {
+            Condition::MotorStatus { motor, status } => {
+                value(Read::Motor(*motor)) == Some(Value::Status(*status))
+            }
+            Condition::Input { input, high } => {
+                value(Read::Input(*input)) == Some(Value::Level(*high))
+            }
+            Condition::All(conditions) => conditions.iter().all(|c| c.holds(values)),
+            Condition::Any(conditions) => conditions.iter().any(|c| c.holds(values)),
+            Condition::Not(condition) => !condition.holds(values),
+        }
+    }
+}
+
+impl std::ops::Not for Condition {
+    type Output = Condition;
+
+    fn not(self) -> Condition {
+        Condition::Not(Box::new(self))
+    }
+}
+
+/// A condition with how to wait for it: how often to poll (50 ms by default) and for how long
+/// (forever by default).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Wait {
+    condition: Condition,
+    timeout: Option<Duration>,
+    poll: Duration,
+}
+
+impl From<Condition> for Wait {
+    fn from(condition: Condition) -> Self {
+        Self {
+            condition,
+            timeout: None,
+            poll: DEFAULT_POLL,
+        }
+    }
+}
+
+impl Wait {
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn poll(mut self, poll: Duration) -> Self {
+        self.poll = poll;
+        self
+    }
+
+    /// Polls every device the condition depends on, each once per round and all concurrently,
+    /// until it holds.
+    pub(crate) async fn run(&self, controller: &ControllerHandle) -> Result<()> {
+        let mut reads = Vec::new();
+        self.condition.reads(&mut reads);
+        let polling = async {
+            let mut tick_interval = tokio::time::interval(self.poll);
+            tick_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+            loop {
+                tick_interval.tick().await;
+                let values = join_all(reads.iter().map(|&read| read_value(controller, read)))
+                    .await
+                    .into_iter()
+                    .collect::<Result<Vec<_>>>()?;
+                if self.condition.holds(&values) {
+                    return Ok(());
+                }
+            }
+        };
+        match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, polling)
+                .await
+                .map_err(|_| ClearCoreError::Timeout(timeout))
+                .context("condition did not hold in time")?,
+            None => polling.await,
+        }
+    }
+}
+
+type Reading = Pin<Box<dyn Future<Output = Result<(Read, Value)>> + Send>>;
+
+fn read_value(controller: &ControllerHandle, read: Read) -> Reading {
+    match read {
+        Read::Motor(id) => {
+            let motor = controller.get_motor(id);
+            Box::pin(async move {
+                let status = motor?
+                    .get_status()
+                    .await
+                    .with_context(|| format!("motor {id}: status"))?;
+                Ok((read, Value::Status(status)))
+            })
+        }
+        Read::Input(id) => {
+            let input = controller.get_digital_input(id);
+            Box::pin(async move {
+                let high = input?
+                    .get_state()
+                    .await
+                    .with_context(|| format!("digital input {id}: state"))?;
+                Ok((read, Value::Level(high)))
+            })
+        }
+    }
+}
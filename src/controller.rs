@@ -1,176 +1,1626 @@
-use std::array;
+use std::future::Future;
+use std::ops::Range;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use tokio::{
-    net::ToSocketAddrs,
-    sync::{mpsc::channel, oneshot},
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpStream, ToSocketAddrs},
+    sync::{
+        broadcast,
+        mpsc::{self, Sender, WeakSender, channel},
+        oneshot, watch,
+    },
+    task::JoinHandle,
+    time::{Interval, MissedTickBehavior},
 };
 
 use crate::{
+    ascii_to_int,
+    codec::{self, CommandKind, Layout},
+    condition::Wait,
+    diagnostics::Diagnostics,
+    error::{ClearCoreError, ControllerErrorCode},
+    faults::{Device, FAULT_EVENT_CAPACITY, FaultEvent, FaultReporter},
+    group::MotorGroup,
     interface::client,
     io::{AnalogInput, DigitalInput, DigitalOutput, HBridge},
-    motor::{ClearCoreMotor, MotorBuilder},
+    join_all,
+    motor::{ClearCoreMotor, MotionProfile, MotorBuilder, Status},
+    send_recv::{SendRecv, current_context, scope_context, scope_critical, scope_priority},
 };
 
-use anyhow::{Result, anyhow};
+use anyhow::{Context, Result, anyhow};
+use log::{debug, error, info, warn};
+
+#[cfg(feature = "metrics")]
+pub use crate::diagnostics::LatencyStats;
 
 pub const STX: u8 = 2;
 pub const CR: u8 = 13;
 pub const RESULT_IDX: u8 = 3;
 
-const NO_MOTORS: usize = 4;
 const NO_DIGITAL_INPUTS: usize = 3;
-const NO_ANALOG_INPUTS: usize = 4;
+const FIRST_ANALOG_INPUT: u8 = 3;
+const NO_ANALOG_INPUTS: u8 = 4;
 const NO_OUTPUTS: usize = 6;
-const NO_HBRIDGE: usize = 2;
+/// Full-scale output of the stock h-bridges.
+pub const DEFAULT_H_BRIDGE_POWER: i16 = 32700;
+/// Highest motor id: the protocol addresses a device with a single ASCII digit.
+pub const MAX_MOTOR_ID: usize = 9;
 
-const REPLY_IDX: usize = 3;
-const FAILED_REPLY: u8 = b'?';
+pub(crate) const REPLY_IDX: usize = 3;
+pub(crate) const FAILED_REPLY: u8 = b'?';
+/// Replies are handed to devices zero-padded to at least this length.
+pub(crate) const REPLY_LEN: usize = 100;
+/// Default `ControllerConfig::max_frame_len`; no legitimate reply comes close to it.
+pub const DEFAULT_MAX_FRAME_LEN: usize = 1024;
+pub const DEFAULT_CHANNEL_CAPACITY: usize = 10;
 
 #[derive(Debug)]
 pub struct Message {
     pub buffer: Vec<u8>,
-    pub response: oneshot::Sender<Vec<u8>>,
-}
-
-//#[derive(Debug)]
-// pub struct Error {
-//     pub message: String,
-// }
-
-// impl From<anyhow::Error> for Error {
-//     fn from(e: anyhow::Error) -> Self {
-//         Self {
-//             message: e.to_string(),
-//         }
-//     }
-// }
-
-// impl From<std::io::Error> for Error {
-//     fn from(e: std::io::Error) -> Self {
-//         Self {
-//             message: e.to_string(),
-//         }
-//     }
-// }
-
-// impl From<std::str::Utf8Error> for Error {
-//     fn from(e: std::str::Utf8Error) -> Self {
-//         Self {
-//             message: e.to_string(),
-//         }
-//     }
-// }
-
-// impl fmt::Display for Error {
-//     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-//         write!(f, "{}", self.message)
-//     }
-// }
+    pub response: oneshot::Sender<Result<Vec<u8>, ClearCoreError>>,
+    /// Application context of the operation that sent the command, see `with_context`.
+    pub context: Option<Arc<str>>,
+    pub priority: Priority,
+}
+
+/// Order in which the client sends queued commands: highest first, first come first served
+/// within a level. Stop commands always go out at `Priority::STOP`; everything else defaults to
+/// `Priority::NORMAL` unless sent under `with_priority`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Priority(pub u8);
+
+impl Priority {
+    pub const LOW: Priority = Priority(64);
+    pub const NORMAL: Priority = Priority(128);
+    pub const HIGH: Priority = Priority(192);
+    pub const STOP: Priority = Priority(u8::MAX);
+}
 
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::NORMAL
+    }
+}
 
+/// Runs `f` with `context` attached to every command it sends (including from helpers that fan
+/// out to several devices), so client logs and rejected-command errors name the application
+/// operation, e.g. `with_context("pick for order #1234", motor.absolute_move(10.)).await`.
+pub async fn with_context<F: Future>(context: impl Into<Arc<str>>, f: F) -> F::Output {
+    scope_context(Some(context.into()), f).await
+}
+
+/// Runs `f` with every command it sends queued at `priority`, e.g. to have an interlock check
+/// overtake status polls on a congested link. Only reorders commands waiting in the queue; the
+/// one in flight always completes first, and commands in a `critical` section keep their order.
+pub async fn with_priority<F: Future>(priority: Priority, f: F) -> F::Output {
+    scope_priority(Some(priority), f).await
+}
 
 pub(crate) fn check_reply(reply: &[u8]) -> Result<()> {
+    if reply.len() <= REPLY_IDX {
+        return Err(ClearCoreError::BadReply(reply.to_vec()).into());
+    }
     if reply[REPLY_IDX] == FAILED_REPLY {
-        Err(anyhow!(std::str::from_utf8(reply)?.to_string()))
+        let end = reply
+            .iter()
+            .position(|&b| b == CR || b == 0)
+            .unwrap_or(reply.len());
+        Err(ClearCoreError::Rejected {
+            code: ControllerErrorCode::parse(&reply[REPLY_IDX + 1..end]),
+            reply: std::str::from_utf8(&reply[1..end])?.to_string(),
+            context: current_context(),
+        }
+        .into())
     } else {
         Ok(())
     }
 }
 
+/// The code of a rejection, or `None` if `reply` is not one.
+pub(crate) fn rejection_code(reply: &[u8]) -> Option<ControllerErrorCode> {
+    match check_reply(reply).map_err(|e| e.downcast::<ClearCoreError>()) {
+        Err(Ok(ClearCoreError::Rejected { code, .. })) => Some(code),
+        _ => None,
+    }
+}
+
+/// The integer a reply carries, after checking it was not a rejection.
+pub(crate) fn reply_int(reply: &[u8]) -> Result<isize> {
+    check_reply(reply)?;
+    ascii_to_int(&reply[REPLY_IDX..])
+        .ok_or_else(|| ClearCoreError::BadReply(codec::reply_payload(reply).to_vec()))
+        .context("no number in reply")
+}
+
 pub(crate) const fn make_prefix(device_type: u8, device_id: u8) -> [u8; 3] {
     [2, device_type, device_id + 48]
 }
 
-pub type Motors = [ClearCoreMotor; NO_MOTORS];
-pub type HBridges = [HBridge; NO_HBRIDGE];
-pub type AnalogInputs = [AnalogInput; NO_ANALOG_INPUTS];
+/// Prefix of the queries addressed to the controller itself rather than one of its devices.
+const CONTROLLER_PREFIX: [u8; 3] = make_prefix(b'C', 0);
+
+/// Text payload of a reply, without the prefix, CR terminator, or unused buffer space.
+pub(crate) fn reply_text(reply: &[u8]) -> Result<&str> {
+    let text = reply
+        .get(REPLY_IDX..)
+        .and_then(|payload| std::str::from_utf8(payload).ok())
+        .ok_or_else(|| ClearCoreError::BadReply(reply.to_vec()))?;
+    Ok(text.trim_end_matches(['\r', '\0']).trim())
+}
+
+pub type Motors = Vec<ClearCoreMotor>;
+pub type HBridges = Vec<HBridge>;
+pub type AnalogInputs = Vec<AnalogInput>;
 pub type Inputs = Vec<DigitalInput>; //We have a variable number of these due to the IO bank's versatility
 pub type Outputs = Vec<DigitalOutput>; //We have a variable number of these due to the IO bank's versatility
 
+/// How long the client waits for the reply to each kind of command before giving up on it. The
+/// command then fails with `ClearCoreError::Timeout` and the client reconnects, so that a reply
+/// arriving late cannot be taken for the next command's.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CommandTimeouts {
+    pub query: Duration,
+    pub config: Duration,
+    pub motion: Duration,
+    pub enable: Duration,
+    pub stop: Duration,
+}
+
+impl Default for CommandTimeouts {
+    fn default() -> Self {
+        Self {
+            query: Duration::from_millis(250),
+            config: Duration::from_millis(500),
+            motion: Duration::from_secs(1),
+            enable: Duration::from_secs(5),
+            stop: Duration::from_millis(500),
+        }
+    }
+}
+
+impl CommandTimeouts {
+    pub fn for_kind(&self, kind: CommandKind) -> Duration {
+        match kind {
+            CommandKind::Query => self.query,
+            CommandKind::Config => self.config,
+            CommandKind::Motion => self.motion,
+            CommandKind::Enable => self.enable,
+            CommandKind::Stop => self.stop,
+        }
+    }
+}
+
+/// Exponential backoff between reconnection attempts: `base_delay` doubled per failed attempt,
+/// capped at `max_delay`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReconnectPolicy {
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            base_delay: Duration::from_millis(250),
+            max_delay: Duration::from_secs(10),
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay)
+    }
+}
+
+/// Byte stream the client exchanges frames over.
+pub trait ByteStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> ByteStream for T {}
+
+pub type StreamWrapper = Arc<
+    dyn Fn(TcpStream) -> Pin<Box<dyn Future<Output = std::io::Result<Box<dyn ByteStream>>> + Send>>
+        + Send
+        + Sync,
+>;
+
+pub type Connector = Arc<
+    dyn Fn() -> Pin<Box<dyn Future<Output = std::io::Result<Box<dyn ByteStream>>> + Send>>
+        + Send
+        + Sync,
+>;
+
+/// What the client runs the protocol over. Framing is the same either way.
+#[derive(Clone, Default)]
+pub enum Transport {
+    /// The TCP connection as is.
+    #[default]
+    Tcp,
+    /// The stream the wrapper makes of each new TCP connection, e.g. a tunnel for which there
+    /// is no variant of its own. Runs before the handshake, again on every reconnect; an error
+    /// fails the connection attempt.
+    Wrapped(StreamWrapper),
+    /// The stream the connector opens, in place of a TCP connection to the client's address,
+    /// e.g. an in-memory link to a `MockController`. Called on every (re)connect.
+    Connect(Connector),
+    /// A TLS session over each new TCP connection, e.g. to a TLS-terminating proxy in front of
+    /// the controller, verifying the server as `ServerName` under the `ClientConfig`. A failed
+    /// TLS handshake fails the connection attempt.
+    #[cfg(feature = "tls")]
+    Tls(
+        Arc<tokio_rustls::rustls::ClientConfig>,
+        tokio_rustls::rustls::pki_types::ServerName<'static>,
+    ),
+}
+
+impl Transport {
+    /// `Wrapped` in an async `wrapper`, e.g. a tunnel this crate does not provide.
+    pub fn wrap<F, Fut, S>(wrapper: F) -> Self
+    where
+        F: Fn(TcpStream) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = std::io::Result<S>> + Send + 'static,
+        S: ByteStream + 'static,
+    {
+        let wrapper = Arc::new(wrapper);
+        Transport::Wrapped(Arc::new(move |stream| {
+            let stream = wrapper(stream);
+            Box::pin(async move { Ok(Box::new(stream.await?) as Box<dyn ByteStream>) })
+        }))
+    }
+
+    /// `Connect` through an async `connector`.
+    pub fn connect<F, Fut, S>(connector: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = std::io::Result<S>> + Send + 'static,
+        S: ByteStream + 'static,
+    {
+        Transport::Connect(Arc::new(move || {
+            let stream = connector();
+            Box::pin(async move { Ok(Box::new(stream.await?) as Box<dyn ByteStream>) })
+        }))
+    }
+}
+
+impl std::fmt::Debug for Transport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Transport::Tcp => f.write_str("Tcp"),
+            Transport::Wrapped(_) => f.write_str("Wrapped"),
+            Transport::Connect(_) => f.write_str("Connect"),
+            #[cfg(feature = "tls")]
+            Transport::Tls(_, server_name) => f.debug_tuple("Tls").field(server_name).finish(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ControllerConfig {
+    /// Query the controller must answer without `?` before any command is sent, e.g. a status
+    /// query. Until it does, commands stay queued instead of reaching a controller that is not
+    /// ready yet.
+    pub handshake: Option<Vec<u8>>,
+    /// Motion profile applied to motor `id` by `apply_config`.
+    pub motor_profiles: Vec<(usize, MotionProfile)>,
+    /// Firmware input filter applied to digital input `id` by `apply_config`.
+    pub input_debounce: Vec<(usize, Duration)>,
+    /// Enable every motor once the settings above are in place.
+    pub enable_motors: bool,
+    pub timeouts: CommandTimeouts,
+    /// Longest reply accepted without a CR terminator before the client gives up on the frame
+    /// and reconnects.
+    pub max_frame_len: usize,
+    /// How to reconnect after the connection drops or cannot be made; `None` stops the client
+    /// instead, failing every later command.
+    pub reconnect: Option<ReconnectPolicy>,
+    /// Commands that can wait for the client before senders have to. The client sends one
+    /// command at a time, so once this many are queued each further command waits in `send`
+    /// for a slot, still counted against its timeout. At least 1.
+    pub channel_capacity: usize,
+    /// Check that each reply starts by echoing the command's `STX, device, id` and fail the
+    /// command with `ClearCoreError::Corrupted` if not, e.g. because a byte was garbled on the
+    /// way. Unlike a `?` rejection such a command is safe to resend, once the client has
+    /// reconnected.
+    pub verify_echo: bool,
+    /// Firmware versions `connect` accepts, as reported by `firmware_version`; it refuses any
+    /// other. Empty accepts any version, including firmware that cannot report one.
+    pub supported_firmware: Vec<String>,
+    pub transport: Transport,
+}
+
+impl Default for ControllerConfig {
+    fn default() -> Self {
+        Self {
+            handshake: None,
+            motor_profiles: Vec::new(),
+            input_debounce: Vec::new(),
+            enable_motors: false,
+            timeouts: CommandTimeouts::default(),
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+            reconnect: Some(ReconnectPolicy::default()),
+            channel_capacity: DEFAULT_CHANNEL_CAPACITY,
+            verify_echo: false,
+            supported_firmware: Vec::new(),
+            transport: Transport::Tcp,
+        }
+    }
+}
+
+impl ControllerConfig {
+    pub fn with_handshake(mut self, handshake: &[u8]) -> Self {
+        self.handshake = Some(handshake.to_vec());
+        self
+    }
+
+    pub fn with_motor_profile(mut self, id: usize, profile: MotionProfile) -> Self {
+        self.motor_profiles.push((id, profile));
+        self
+    }
+
+    pub fn with_input_debounce(mut self, id: usize, debounce: Duration) -> Self {
+        self.input_debounce.push((id, debounce));
+        self
+    }
+
+    pub fn with_enabled_motors(mut self) -> Self {
+        self.enable_motors = true;
+        self
+    }
+
+    pub fn with_timeouts(mut self, timeouts: CommandTimeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    pub fn with_max_frame_len(mut self, max_frame_len: usize) -> Self {
+        self.max_frame_len = max_frame_len;
+        self
+    }
+
+    pub fn with_reconnect(mut self, reconnect: Option<ReconnectPolicy>) -> Self {
+        self.reconnect = reconnect;
+        self
+    }
+
+    pub fn with_channel_capacity(mut self, channel_capacity: usize) -> Self {
+        self.channel_capacity = channel_capacity;
+        self
+    }
+
+    pub fn with_echo_verification(mut self) -> Self {
+        self.verify_echo = true;
+        self
+    }
+
+    pub fn with_supported_firmware(mut self, version: &str) -> Self {
+        self.supported_firmware.push(version.to_string());
+        self
+    }
+
+    pub fn with_transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+}
+
+type Step = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+/// Folds the results of independent steps into one error naming every step that failed. A
+/// single failure is passed on as is, so a `ClearCoreError` in it can still be downcast.
+pub(crate) fn collect_errors(results: impl IntoIterator<Item = Result<()>>) -> Result<()> {
+    let mut errors: Vec<anyhow::Error> = results.into_iter().filter_map(Result::err).collect();
+    match errors.len() {
+        0 => Ok(()),
+        1 => Err(errors.remove(0)),
+        _ => {
+            let errors: Vec<String> = errors.iter().map(|e| format!("{e:#}")).collect();
+            Err(anyhow!(errors.join("; ")))
+        }
+    }
+}
+
+const SHUTDOWN_POLL: Duration = Duration::from_millis(50);
+const COORDINATED_MOVE_POLL: Duration = Duration::from_millis(50);
+/// How long `responding_motors` gives each motor to answer.
+const PING_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// What `prepare_shutdown` does besides stopping, braking and disabling every motor.
+#[derive(Clone, Debug)]
+pub struct ShutdownConfig {
+    /// How long motors get to come to rest after the soft stop.
+    pub settle_timeout: Duration,
+    /// Digital output `id` and the state it must be left in.
+    pub safe_outputs: Vec<(usize, bool)>,
+    /// Read every motor's position before power is removed.
+    pub snapshot_positions: bool,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            settle_timeout: Duration::from_secs(5),
+            safe_outputs: Vec::new(),
+            snapshot_positions: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ShutdownReport {
+    /// Positions read before shutdown, in motor order; `None` if not requested or the read
+    /// failed.
+    pub positions: Vec<Option<f64>>,
+    /// Every step that failed, in the order they ran.
+    pub errors: Vec<String>,
+}
+
+impl ShutdownReport {
+    pub fn is_clean(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    fn record(&mut self, result: Result<()>) {
+        if let Err(e) = result {
+            self.errors.push(format!("{e:#}"));
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PowerStatus {
+    pub supply_voltage: Option<f64>,
+    pub power_good: Option<bool>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connecting,
+    Handshaking,
+    Ready,
+    /// The connection was lost; waiting out the backoff before connecting again. Commands fail
+    /// with `ClearCoreError::Transport(NotConnected)` meanwhile.
+    Reconnecting,
+    /// The client task stopped after a shutdown or once every handle was dropped.
+    Closed,
+    /// The client task stopped because the connection failed and no reconnect was configured.
+    Failed,
+}
+
+/// Coarse view of `ConnectionState` for supervisors, see `ControllerHandle::connection_status`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionStatus {
+    Connected,
+    /// Not connected right now, but connecting or due to, or closed on request.
+    Disconnected,
+    /// The client task gave up; the handle has to be rebuilt.
+    Failed,
+}
+
+impl From<ConnectionState> for ConnectionStatus {
+    fn from(state: ConnectionState) -> Self {
+        match state {
+            ConnectionState::Ready => ConnectionStatus::Connected,
+            ConnectionState::Failed => ConnectionStatus::Failed,
+            ConnectionState::Connecting
+            | ConnectionState::Handshaking
+            | ConnectionState::Reconnecting
+            | ConnectionState::Closed => ConnectionStatus::Disconnected,
+        }
+    }
+}
+
+/// Marks every motor's position lost whenever the client comes back after a dropped connection,
+/// as the controller may have rebooted in between, and reconciles each motor's position
+/// estimate with the controller. Holds the client's channel only weakly so that dropping every
+/// handle still stops the client.
+fn watch_reconnects(
+    mut connection: watch::Receiver<ConnectionState>,
+    motors: &[ClearCoreMotor],
+    drive_sender: WeakSender<Message>,
+) {
+    let (detached, _) = channel(1);
+    let motors: Vec<_> = motors
+        .iter()
+        .map(|motor| motor.with_drive_sender(detached.clone()))
+        .collect();
+    tokio::spawn(async move {
+        let mut was_ready = false;
+        while connection.changed().await.is_ok() {
+            if *connection.borrow_and_update() != ConnectionState::Ready {
+                continue;
+            }
+            if was_ready {
+                warn!("Reconnected to controller; marking motor positions lost");
+                for motor in &motors {
+                    motor.mark_position_lost();
+                }
+                let Some(drive_sender) = drive_sender.upgrade() else {
+                    break;
+                };
+                for motor in &motors {
+                    let motor = motor.with_drive_sender(drive_sender.clone());
+                    if let Err(e) = motor.reconcile().await {
+                        warn!("Motor {} position after reconnect: {e:#}", motor.id);
+                    }
+                }
+            }
+            was_ready = true;
+        }
+    });
+}
+
+fn queue_len(sender: &Sender<Message>) -> usize {
+    sender.max_capacity() - sender.capacity()
+}
+
+/// Paces routine polling so it yields to queued commands: each `tick` waits for the next
+/// period and then, while more than `threshold` commands are waiting to be sent, keeps
+/// postponing the poll by `backoff`.
+pub struct AdaptivePoller {
+    interval: Interval,
+    backoff: Duration,
+    threshold: usize,
+    drive_sender: Sender<Message>,
+}
+
+impl AdaptivePoller {
+    pub async fn tick(&mut self) {
+        self.interval.tick().await;
+        while queue_len(&self.drive_sender) > self.threshold {
+            tokio::time::sleep(self.backoff).await;
+        }
+    }
+}
+
+/// Layout of the devices on one controller, for machines that differ from the stock ClearCore:
+/// by default no motors, digital inputs 0-2, analog inputs 3-6, outputs 0-5 and h-bridges 4 and
+/// 5 at `DEFAULT_H_BRIDGE_POWER`. Devices are indexed by `ControllerHandle`'s getters in the
+/// order given here.
 #[derive(Clone)]
-pub struct ControllerHandle {
-    motors: Motors,
-    digital_inputs: Inputs,
-    analog_inputs: AnalogInputs,
-    outputs: Outputs,
-    h_bridges: HBridges,
+pub struct ControllerBuilder {
+    motors: Vec<MotorBuilder>,
+    digital_inputs: usize,
+    analog_inputs: Range<u8>,
+    outputs: usize,
+    h_bridges: Vec<(u8, i16)>,
+    config: ControllerConfig,
 }
 
-impl ControllerHandle {
-    pub fn new<T>(addr: T, builder: [MotorBuilder; 4]) -> Self
+impl Default for ControllerBuilder {
+    fn default() -> Self {
+        Self {
+            motors: Vec::new(),
+            digital_inputs: NO_DIGITAL_INPUTS,
+            analog_inputs: FIRST_ANALOG_INPUT..FIRST_ANALOG_INPUT + NO_ANALOG_INPUTS,
+            outputs: NO_OUTPUTS,
+            h_bridges: [4, 5].map(|id| (id, DEFAULT_H_BRIDGE_POWER)).to_vec(),
+            config: ControllerConfig::default(),
+        }
+    }
+}
+
+impl ControllerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the next motor; `get_motor` numbers motors in the order they were added.
+    pub fn motor(mut self, motor: MotorBuilder) -> Self {
+        self.motors.push(motor);
+        self
+    }
+
+    pub fn motors(mut self, motors: impl IntoIterator<Item = MotorBuilder>) -> Self {
+        self.motors.extend(motors);
+        self
+    }
+
+    /// Digital inputs `0..count`.
+    pub fn digital_inputs(mut self, count: usize) -> Self {
+        self.digital_inputs = count;
+        self
+    }
+
+    /// Analog inputs by controller id; `get_analog_input(0)` is the first of `ids`.
+    pub fn analog_inputs(mut self, ids: Range<u8>) -> Self {
+        self.analog_inputs = ids;
+        self
+    }
+
+    /// Digital outputs `0..count`.
+    pub fn outputs(mut self, count: usize) -> Self {
+        self.outputs = count;
+        self
+    }
+
+    /// Adds h-bridge `id` with `max_power` as its full-scale output, or changes it if already
+    /// present.
+    pub fn h_bridge(mut self, id: u8, max_power: i16) -> Self {
+        match self
+            .h_bridges
+            .iter_mut()
+            .find(|(existing, _)| *existing == id)
+        {
+            Some(h_bridge) => h_bridge.1 = max_power,
+            None => self.h_bridges.push((id, max_power)),
+        }
+        self
+    }
+
+    /// Drops the default h-bridges, e.g. when their pins are wired as plain outputs.
+    pub fn without_h_bridges(mut self) -> Self {
+        self.h_bridges.clear();
+        self
+    }
+
+    pub fn with_config(mut self, config: ControllerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Spawns the client task and returns the handle right away, see `ControllerHandle::new`.
+    /// `addr` must be `Sync` because every reconnect resolves it again from the client task.
+    /// Errors with `ClearCoreError::InvalidParameter` if a motor id is above `MAX_MOTOR_ID`.
+    pub fn build<T>(self, addr: T) -> Result<ControllerHandle>
     where
-        T: ToSocketAddrs + Send + 'static,
+        T: ToSocketAddrs + Send + Sync + 'static,
     {
-        let (tx, rx) = channel::<Message>(10);
-        tokio::spawn(async move {
-            client(addr, rx).await.unwrap();
-        });
-        let motors = array::from_fn(|i| {
-            let builder = builder[i].clone();
-            ClearCoreMotor::new(builder.id, builder.scale, tx.clone())
+        if let Some(motor) = self.motors.iter().find(|motor| motor.id > MAX_MOTOR_ID) {
+            return Err(ClearCoreError::InvalidParameter {
+                parameter: "motor id",
+                value: motor.id as f64,
+            })
+            .context(format!("motor ids go up to {MAX_MOTOR_ID}"));
+        }
+        let config = self.config;
+        let (tx, rx) = channel::<Message>(config.channel_capacity.max(1));
+        let (sections, sections_rx) = channel(1);
+        let (state_tx, connection) = watch::channel(ConnectionState::Connecting);
+        let diagnostics = Arc::new(Diagnostics::default());
+        let client_diagnostics = diagnostics.clone();
+        let (fault_events, _) = broadcast::channel(FAULT_EVENT_CAPACITY);
+        let (stop, stop_rx) = watch::channel(false);
+        let motor_profiles = config.motor_profiles.clone();
+        let client = tokio::spawn(async move {
+            if let Err(e) = client(
+                addr,
+                rx,
+                sections_rx,
+                stop_rx,
+                config,
+                state_tx,
+                client_diagnostics,
+            )
+            .await
+            {
+                error!("Client stopped: {e}");
+            }
         });
+        let motors: Motors = self
+            .motors
+            .into_iter()
+            .enumerate()
+            .map(|(i, builder)| {
+                let faults =
+                    FaultReporter::new(fault_events.clone(), Device::Motor(builder.id as u8));
+                let motor = ClearCoreMotor::new(builder.id, builder.scale, tx.clone())
+                    .with_homing_policy(builder.homing)
+                    .with_settle_delay(builder.settle_delay)
+                    .with_min_cycle_interval(builder.min_cycle_interval)
+                    .with_fault_reporter(faults);
+                let motor = if builder.invert {
+                    motor.with_inverted()
+                } else {
+                    motor
+                };
+                let motor = match builder.limits {
+                    Some((min, max)) => motor.with_soft_limits(min, max),
+                    None => motor,
+                }
+                .with_limit_mode(builder.limit_mode);
+                let motor = match motor_profiles.iter().find(|(id, _)| *id == i) {
+                    Some((_, profile)) => motor.with_default_profile(profile.clone()),
+                    None => motor,
+                };
+                match builder.brake {
+                    Some(output) => motor.with_brake(DigitalOutput::new(output, tx.clone())),
+                    None => motor,
+                }
+            })
+            .collect();
 
-        let digital_inputs = (0..NO_DIGITAL_INPUTS)
+        let digital_inputs = (0..self.digital_inputs)
             .map(|index| DigitalInput::new(index as u8, tx.clone()))
             .collect();
 
-        let analog_inputs = array::from_fn(|i| AnalogInput::new(i as u8 + 3, tx.clone()));
+        let analog_inputs = self
+            .analog_inputs
+            .map(|id| AnalogInput::new(id, tx.clone()))
+            .collect();
 
-        let outputs = (0..NO_OUTPUTS)
+        let outputs = (0..self.outputs)
             .map(|index| DigitalOutput::new(index as u8, tx.clone()))
             .collect();
 
-        let h_bridges = [
-            HBridge::new(4, 32700, tx.clone()),
-            HBridge::new(5, 32700, tx.clone()),
-        ];
+        let h_bridges = self
+            .h_bridges
+            .into_iter()
+            .map(|(id, power)| {
+                let faults = FaultReporter::new(fault_events.clone(), Device::HBridge(id));
+                HBridge::new(id, power, tx.clone()).with_fault_reporter(faults)
+            })
+            .collect();
+
+        watch_reconnects(connection.clone(), &motors, tx.downgrade());
 
-        Self {
+        Ok(ControllerHandle {
             motors,
             digital_inputs,
             analog_inputs,
             outputs,
             h_bridges,
+            connection,
+            diagnostics,
+            sections,
+            fault_events,
+            stop,
+            client: Arc::new(Mutex::new(Some(client))),
+            drive_sender: tx,
+        })
+    }
+
+    /// Builds the handle, waits for the controller to be ready and applies the startup
+    /// settings, see `ControllerHandle::connect`.
+    pub async fn connect<T>(self, addr: T) -> Result<ControllerHandle>
+    where
+        T: ToSocketAddrs + Send + Sync + 'static,
+    {
+        let config = self.config.clone();
+        let controller = self.build(addr)?;
+        controller.wait_ready().await?;
+        controller.check_firmware(&config).await?;
+        controller.apply_config(&config).await?;
+        Ok(controller)
+    }
+}
+
+/// Handle to one controller. Constructing it (`new`, `with_config`, `connect`) spawns the client
+/// task that owns the only socket to the controller; cloning the handle (or calling `share`)
+/// does not, so any number of modules can hold handles that all go through that one socket.
+#[derive(Clone)]
+pub struct ControllerHandle {
+    motors: Motors,
+    digital_inputs: Inputs,
+    analog_inputs: AnalogInputs,
+    outputs: Outputs,
+    h_bridges: HBridges,
+    connection: watch::Receiver<ConnectionState>,
+    #[cfg_attr(not(feature = "history"), allow(dead_code))]
+    diagnostics: Arc<Diagnostics>,
+    sections: Sender<mpsc::Receiver<Message>>,
+    fault_events: broadcast::Sender<FaultEvent>,
+    stop: watch::Sender<bool>,
+    client: Arc<Mutex<Option<JoinHandle<()>>>>,
+    drive_sender: Sender<Message>,
+}
+
+impl SendRecv for ControllerHandle {
+    fn get_sender(&self) -> &Sender<Message> {
+        &self.drive_sender
+    }
+}
+
+impl ControllerHandle {
+    /// Takes one builder per motor, in id order as `get_motor` indexes them: as many as the
+    /// machine has, e.g. more than four with a CCIO-8 expansion or one on a test rig. `addr` is
+    /// resolved again on every reconnect, hence the `Sync` bound. Errors if a motor id is above
+    /// `MAX_MOTOR_ID`.
+    pub fn new<T>(addr: T, builder: impl IntoIterator<Item = MotorBuilder>) -> Result<Self>
+    where
+        T: ToSocketAddrs + Send + Sync + 'static,
+    {
+        Self::with_config(addr, builder, ControllerConfig::default())
+    }
+
+    pub fn with_config<T>(
+        addr: T,
+        builder: impl IntoIterator<Item = MotorBuilder>,
+        config: ControllerConfig,
+    ) -> Result<Self>
+    where
+        T: ToSocketAddrs + Send + Sync + 'static,
+    {
+        ControllerBuilder::new()
+            .motors(builder)
+            .with_config(config)
+            .build(addr)
+    }
+
+    /// Every fault any status read sees, across all motors and h-bridges: a device is reported
+    /// when it goes into a fault (or a different one), not on every read while it stays there.
+    /// Reads made anywhere feed it, including `wait_for_move` and `wait_for_any_fault`; run
+    /// `monitor_faults` to have them made in the background.
+    pub fn fault_events(&self) -> broadcast::Receiver<FaultEvent> {
+        self.fault_events.subscribe()
+    }
+
+    /// Reads every motor's and h-bridge's status once per `interval` so faults reach
+    /// `fault_events` without anything else polling. Runs until aborted or the client stops.
+    pub fn monitor_faults(&self, interval: Duration) -> JoinHandle<()> {
+        let handle = self.clone();
+        tokio::spawn(async move {
+            let mut tick_interval = tokio::time::interval(interval);
+            tick_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+            while handle.is_client_alive() {
+                tick_interval.tick().await;
+                let mut steps: Vec<Step> = Vec::new();
+                for motor in handle.motors.clone() {
+                    steps.push(Box::pin(async move {
+                        motor
+                            .get_status()
+                            .await
+                            .map(|_| ())
+                            .with_context(|| format!("motor {}: status", motor.id))
+                    }));
+                }
+                for h_bridge in handle.h_bridges.clone() {
+                    steps.push(Box::pin(async move {
+                        h_bridge
+                            .status()
+                            .await
+                            .map(|_| ())
+                            .context("h-bridge status")
+                    }));
+                }
+                if let Err(e) = collect_errors(join_all(steps).await) {
+                    warn!("Fault monitor: {e}");
+                }
+            }
+        })
+    }
+
+    /// Runs `f` with the connection to itself: commands `f` sends, from any device handle and
+    /// through the crate's fan-out helpers, go out with nothing from other tasks or handle
+    /// clones in between. Other commands queue until `f` finishes, so keep it short. Commands
+    /// from tasks `f` spawns itself are not part of the section and would wait for it to end.
+    pub async fn critical<F, Fut, T>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = T>,
+    {
+        let (section, section_rx) = channel(DEFAULT_CHANNEL_CAPACITY);
+        self.sections
+            .send(section_rx)
+            .await
+            .map_err(|_| ClearCoreError::Disconnected)?;
+        Ok(scope_critical(section, f()).await)
+    }
+
+    /// The last rejected (`?`) commands with their replies, oldest first, as raw frames.
+    #[cfg(feature = "history")]
+    pub fn recent_rejections(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.diagnostics.recent_rejections()
+    }
+
+    /// Round trips of the last 256 answered commands, timed by the client from writing the
+    /// command to reading its reply, so queueing behind other commands is left out. `None`
+    /// before the first reply.
+    #[cfg(feature = "metrics")]
+    pub fn latency_stats(&self) -> Option<LatencyStats> {
+        self.diagnostics.latency_stats()
+    }
+
+    /// Connects, waits for the controller to be ready and applies the startup settings in
+    /// `config`.
+    pub async fn connect<T>(
+        addr: T,
+        builder: impl IntoIterator<Item = MotorBuilder>,
+        config: ControllerConfig,
+    ) -> Result<Self>
+    where
+        T: ToSocketAddrs + Send + Sync + 'static,
+    {
+        ControllerBuilder::new()
+            .motors(builder)
+            .with_config(config)
+            .connect(addr)
+            .await
+    }
+
+    /// Sends all profiles and input filters without waiting on each reply, then enables the
+    /// motors concurrently if requested. The error names every step that failed.
+    pub async fn apply_config(&self, config: &ControllerConfig) -> Result<()> {
+        let mut steps: Vec<Step> = Vec::new();
+        for (id, profile) in &config.motor_profiles {
+            let (id, profile) = (*id, profile.clone());
+            let motor = self.motors.get(id).cloned();
+            steps.push(Box::pin(async move {
+                let motor = motor.ok_or_else(|| anyhow!("no motor {id}"))?;
+                motor
+                    .apply_profile(&profile)
+                    .await
+                    .with_context(|| format!("motor {id}: apply profile"))
+            }));
+        }
+        for (id, debounce) in &config.input_debounce {
+            let (id, debounce) = (*id, *debounce);
+            let input = self.digital_inputs.get(id).cloned();
+            steps.push(Box::pin(async move {
+                let input = input.ok_or_else(|| anyhow!("no digital input {id}"))?;
+                input
+                    .set_debounce(debounce)
+                    .await
+                    .with_context(|| format!("digital input {id}: set debounce"))
+            }));
         }
+        collect_errors(join_all(steps).await)?;
+        if config.enable_motors {
+            collect_errors(
+                join_all(self.motors.iter().cloned().map(|motor| async move {
+                    motor
+                        .enable()
+                        .await
+                        .with_context(|| format!("motor {}: enable", motor.id))
+                }))
+                .await,
+            )?;
+        }
+        Ok(())
     }
 
-    pub fn get_motor(&self, id: usize) -> ClearCoreMotor {
-        self.motors[id].clone()
+    /// Another handle to the same client task and socket; equivalent to `clone`.
+    pub fn share(&self) -> Self {
+        self.clone()
+    }
+
+    /// Commands queued for the client task but not yet picked up.
+    pub fn queue_len(&self) -> usize {
+        queue_len(&self.drive_sender)
+    }
+
+    pub fn adaptive_poller(
+        &self,
+        period: Duration,
+        backoff: Duration,
+        threshold: usize,
+    ) -> AdaptivePoller {
+        let mut interval = tokio::time::interval(period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        AdaptivePoller {
+            interval,
+            backoff,
+            threshold,
+            drive_sender: self.drive_sender.clone(),
+        }
+    }
+
+    /// Whether the background client task is still running. Once it has exited (connection lost
+    /// or closed) commands can no longer reach the controller and the handle must be rebuilt.
+    pub fn is_client_alive(&self) -> bool {
+        !self.drive_sender.is_closed()
+    }
+
+    /// Ids of the motors that answered a status query within half a second, e.g. to refuse to
+    /// start when a drive is unplugged or unpowered. All motors are asked at once.
+    pub async fn responding_motors(&self) -> Vec<usize> {
+        join_all(self.motors.iter().cloned().map(|motor| async move {
+            tokio::time::timeout(PING_TIMEOUT, motor.get_status())
+                .await
+                .is_ok_and(|status| status.is_ok())
+                .then_some(motor.id as usize)
+        }))
+        .await
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    /// Stops the client task: the command in flight gets its reply, commands still queued fail,
+    /// and the connection is closed. Resolves once the task has exited. Commands sent through
+    /// any clone of the handle afterwards fail instead of waiting. Does not touch the motors;
+    /// see `prepare_shutdown`.
+    pub async fn shutdown(self) -> Result<()> {
+        self.stop.send_replace(true);
+        let client = self.client.lock().expect("client handle poisoned").take();
+        match client {
+            Some(client) => client.await.context("client task panicked"),
+            // Another clone already shut down, or is awaiting the task.
+            None => Ok(()),
+        }
+    }
+
+    /// Resolves once the controller has answered the handshake and commands are being sent. With
+    /// reconnection enabled, keeps waiting through failed connection attempts.
+    pub async fn wait_ready(&self) -> Result<()> {
+        let mut connection = self.connection.clone();
+        let state = *connection
+            .wait_for(|state| {
+                matches!(
+                    state,
+                    ConnectionState::Ready | ConnectionState::Closed | ConnectionState::Failed
+                )
+            })
+            .await?;
+        match state {
+            ConnectionState::Ready => Ok(()),
+            ConnectionState::Failed => {
+                Err(ClearCoreError::Disconnected).context("connection to controller failed")
+            }
+            _ => Err(ClearCoreError::Disconnected).context("connection to controller closed"),
+        }
+    }
+
+    pub fn connection_status(&self) -> ConnectionStatus {
+        (*self.connection.borrow()).into()
+    }
+
+    /// The client's connection state as it changes, e.g. for a supervisor to await
+    /// `ConnectionState::Failed` and rebuild the handle.
+    pub fn connection_state(&self) -> watch::Receiver<ConnectionState> {
+        self.connection.clone()
+    }
+
+    /// Waits until `condition` holds, e.g. motor 0 ready and input 2 high for at most 5 s:
+    /// `wait_for(Condition::all([motor_ready(0), input_high(2)]).timeout(five_secs))`. Fails
+    /// with `ClearCoreError::Timeout` if the timeout elapses first, or if a read fails.
+    pub async fn wait_for(&self, condition: impl Into<Wait>) -> Result<()> {
+        condition.into().run(self).await
+    }
+
+    pub fn get_motor(&self, id: usize) -> Result<ClearCoreMotor> {
+        self.motors
+            .get(id)
+            .cloned()
+            .ok_or_else(|| anyhow!("no motor {id}, controller has {}", self.motors.len()))
     }
 
     pub fn get_motors(&self) -> Motors {
         self.motors.clone()
     }
 
-    pub fn get_digital_input(&self, id: usize) -> DigitalInput {
-        self.digital_inputs[id].clone()
+    pub fn get_motor_group(&self, ids: &[usize]) -> Result<MotorGroup> {
+        let motors = ids
+            .iter()
+            .map(|&id| self.get_motor(id))
+            .collect::<Result<_>>()?;
+        Ok(MotorGroup::new(motors))
+    }
+
+    pub fn get_digital_input(&self, id: usize) -> Result<DigitalInput> {
+        self.digital_inputs.get(id).cloned().ok_or_else(|| {
+            anyhow!(
+                "no digital input {id}, controller has {}",
+                self.digital_inputs.len()
+            )
+        })
     }
 
     pub fn get_digital_inputs(&self) -> Inputs {
         self.digital_inputs.clone()
     }
 
-    pub fn get_analog_input(&self, id: usize) -> AnalogInput {
-        self.analog_inputs[id].clone()
+    pub fn get_analog_input(&self, id: usize) -> Result<AnalogInput> {
+        self.analog_inputs.get(id).cloned().ok_or_else(|| {
+            anyhow!(
+                "no analog input {id}, controller has {}",
+                self.analog_inputs.len()
+            )
+        })
     }
 
     pub fn get_analog_inputs(&self) -> AnalogInputs {
         self.analog_inputs.clone()
     }
-    pub fn get_output(&self, id: usize) -> DigitalOutput {
-        self.outputs[id].clone()
+    pub fn get_output(&self, id: usize) -> Result<DigitalOutput> {
+        self.outputs
+            .get(id)
+            .cloned()
+            .ok_or_else(|| anyhow!("no output {id}, controller has {}", self.outputs.len()))
     }
 
     pub fn get_outputs(&self) -> Outputs {
         self.outputs.clone()
     }
 
-    pub fn get_h_bridge(&self, id: usize) -> HBridge {
-        let idx = id - 4;
-        self.h_bridges[idx].clone()
+    pub fn get_h_bridge(&self, id: usize) -> Result<HBridge> {
+        self.h_bridges
+            .iter()
+            .find(|h_bridge| h_bridge.id() as usize == id)
+            .cloned()
+            .ok_or_else(|| anyhow!("no h-bridge {id}, controller has {}", self.h_bridges.len()))
     }
 
     pub fn get_h_bridges(&self) -> HBridges {
         self.h_bridges.clone()
     }
+
+    /// Stops every motor at its deceleration, see `abrupt_stop_all` for an emergency stop.
+    pub async fn stop_all(&self) -> Result<()> {
+        self.for_all_motors("stop", |motor| async move { motor.stop().await })
+            .await
+    }
+
+    /// Stops every motor as fast as it can. The stop commands are queued together rather than
+    /// each waiting for the previous reply, and like every stop they go out at `Priority::STOP`,
+    /// ahead of anything already queued. The error names every motor that failed.
+    pub async fn abrupt_stop_all(&self) -> Result<()> {
+        self.for_all_motors(
+            "abrupt stop",
+            |motor| async move { motor.abrupt_stop().await },
+        )
+        .await
+    }
+
+    async fn for_all_motors<F, Fut>(&self, what: &str, command: F) -> Result<()>
+    where
+        F: Fn(ClearCoreMotor) -> Fut,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        collect_errors(
+            join_all(self.motors.iter().cloned().map(|motor| {
+                let id = motor.id;
+                let command = command(motor);
+                let what = what.to_string();
+                async move { command.await.with_context(|| format!("motor {id}: {what}")) }
+            }))
+            .await,
+        )
+    }
+
+    /// Sets each motor's deceleration to `decels[id]` and stops it, all motors concurrently. The
+    /// new decelerations stay in effect for later moves. The error names every motor that failed.
+    pub async fn soft_stop_all_with(&self, decels: &[f64]) -> Result<()> {
+        if decels.len() != self.motors.len() {
+            return Err(anyhow!(
+                "expected {} decelerations, got {}",
+                self.motors.len(),
+                decels.len()
+            ));
+        }
+        collect_errors(
+            join_all(self.motors.iter().cloned().zip(decels.iter().copied()).map(
+                |(motor, decel)| async move {
+                    async {
+                        motor.set_deceleration(decel).await?;
+                        motor.stop().await
+                    }
+                    .await
+                    .with_context(|| format!("motor {}: soft stop", motor.id))
+                },
+            ))
+            .await,
+        )
+    }
+
+    /// Moves every motor to its entry in `targets`, one per motor, so that all axes arrive
+    /// together, and waits for them. The axis that takes longest at its current velocity (as set
+    /// through `set_velocity`, otherwise read back with `get_velocity`) sets the pace, and the
+    /// moves are issued as a `MotorGroup::linear_move` at the matching feedrate; velocities are
+    /// restored afterwards. Acceleration ramps are not accounted for, so axes with different
+    /// ramps finish slightly apart. If any axis fails to start, every moving axis is stopped and
+    /// its velocity restored. Fails naming every axis that faulted or could not be commanded.
+    pub async fn coordinated_move(&self, targets: &[f64]) -> Result<()> {
+        if targets.len() != self.motors.len() {
+            return Err(anyhow!(
+                "expected {} targets, got {}",
+                self.motors.len(),
+                targets.len()
+            ));
+        }
+        let group = MotorGroup::new(self.motors.clone());
+        let positions = group.positions().await?;
+        let moving: Vec<(ClearCoreMotor, f64)> = self
+            .motors
+            .iter()
+            .zip(targets)
+            .zip(&positions)
+            .filter(|((_, target), position)| *target != *position)
+            .map(|((motor, &target), position)| (motor.clone(), (target - position).abs()))
+            .collect();
+        if moving.is_empty() {
+            return Ok(());
+        }
+        let velocities = join_all(moving.iter().cloned().map(|(motor, _)| async move {
+            let velocity = match motor.commanded_velocity() {
+                Some(velocity) => velocity,
+                None => motor.get_velocity().await?,
+            };
+            if velocity > 0. {
+                Ok(velocity)
+            } else {
+                Err(anyhow!("motor {}: velocity is {velocity}", motor.id))
+            }
+        }))
+        .await
+        .into_iter()
+        .collect::<Result<Vec<f64>>>()?;
+        let axes: Vec<(ClearCoreMotor, f64)> = moving
+            .iter()
+            .map(|(motor, _)| motor.clone())
+            .zip(velocities)
+            .collect();
+        let duration = moving
+            .iter()
+            .zip(&axes)
+            .map(|((_, distance), (_, velocity))| distance / velocity)
+            .fold(0., f64::max);
+        let path_length = moving
+            .iter()
+            .map(|(_, distance)| distance * distance)
+            .sum::<f64>()
+            .sqrt();
+        let restore = || async {
+            collect_errors(
+                join_all(axes.iter().cloned().map(|(motor, velocity)| async move {
+                    motor
+                        .set_velocity(velocity)
+                        .await
+                        .with_context(|| format!("motor {}: restore velocity", motor.id))
+                }))
+                .await,
+            )
+        };
+        if let Err(e) = group
+            .linear_move_from(&positions, targets, path_length / duration)
+            .await
+        {
+            let stopped = collect_errors(
+                join_all(axes.iter().cloned().map(|(motor, _)| async move {
+                    motor
+                        .stop()
+                        .await
+                        .with_context(|| format!("motor {}: stop after failed start", motor.id))
+                }))
+                .await,
+            );
+            let restored = restore().await;
+            return collect_errors([Err(e.context("start coordinated move")), stopped, restored]);
+        }
+        let moved = collect_errors(
+            join_all(axes.iter().cloned().map(|(motor, _)| async move {
+                async {
+                    motor.wait_for_move(COORDINATED_MOVE_POLL).await?;
+                    if motor.get_status().await? == Status::Faulted {
+                        return Err(anyhow::Error::from(ClearCoreError::Faulted));
+                    }
+                    Ok(())
+                }
+                .await
+                .with_context(|| format!("motor {}: coordinated move", motor.id))
+            }))
+            .await,
+        );
+        moved.and(restore().await)
+    }
+
+    /// Powers the machine down in order: soft-stop every motor, wait for them to settle, drive
+    /// outputs to their safe states, optionally snapshot positions, engage brakes, then disable
+    /// the motors. A failed step is recorded in the report and the sequence carries on, so
+    /// brakes are still engaged and motors disabled; check `ShutdownReport::is_clean`.
+    pub async fn prepare_shutdown(&self, cfg: ShutdownConfig) -> Result<ShutdownReport> {
+        let mut report = ShutdownReport::default();
+        report.record(collect_errors(
+            join_all(self.motors.iter().cloned().map(|motor| async move {
+                motor
+                    .stop()
+                    .await
+                    .with_context(|| format!("motor {}: soft stop", motor.id))
+            }))
+            .await,
+        ));
+        let settle_timeout = cfg.settle_timeout;
+        report.record(collect_errors(
+            join_all(self.motors.iter().cloned().map(|motor| async move {
+                match tokio::time::timeout(settle_timeout, motor.wait_for_move(SHUTDOWN_POLL)).await
+                {
+                    Ok(res) => res.with_context(|| format!("motor {}: settle", motor.id)),
+                    Err(_) => Err(anyhow!(
+                        "motor {}: not settled within {settle_timeout:?}",
+                        motor.id
+                    )),
+                }
+            }))
+            .await,
+        ));
+        report.record(collect_errors(
+            join_all(cfg.safe_outputs.iter().map(|&(id, state)| {
+                let output = self.outputs.get(id).cloned();
+                async move {
+                    let output = output.ok_or_else(|| anyhow!("no digital output {id}"))?;
+                    output
+                        .set_state(state)
+                        .await
+                        .with_context(|| format!("digital output {id}: set safe state"))
+                }
+            }))
+            .await,
+        ));
+        if cfg.snapshot_positions {
+            for result in join_all(self.motors.iter().cloned().map(|motor| async move {
+                motor
+                    .get_position()
+                    .await
+                    .with_context(|| format!("motor {}: snapshot position", motor.id))
+            }))
+            .await
+            {
+                report.positions.push(result.as_ref().ok().copied());
+                report.record(result.map(|_| ()));
+            }
+        } else {
+            report.positions = vec![None; self.motors.len()];
+        }
+        report.record(collect_errors(
+            join_all(
+                self.motors
+                    .iter()
+                    .filter(|motor| motor.has_brake())
+                    .cloned()
+                    .map(|motor| async move {
+                        motor
+                            .engage_brake()
+                            .await
+                            .with_context(|| format!("motor {}: engage brake", motor.id))
+                    }),
+            )
+            .await,
+        ));
+        report.record(collect_errors(
+            join_all(self.motors.iter().cloned().map(|motor| async move {
+                motor
+                    .disable()
+                    .await
+                    .with_context(|| format!("motor {}: disable", motor.id))
+            }))
+            .await,
+        ));
+        Ok(report)
+    }
+
+    /// Status of every motor, in motor order. The firmware has no all-axes status command, so
+    /// this sends one `GS` query per motor back to back and awaits the replies together.
+    pub async fn get_all_status(&self) -> Result<Vec<Status>> {
+        join_all(
+            self.motors
+                .iter()
+                .cloned()
+                .map(|motor| async move { motor.get_status().await }),
+        )
+        .await
+        .into_iter()
+        .collect()
+    }
+
+    /// Polls every motor's status once per `interval` and resolves with the id of the first
+    /// motor found `Faulted`. Dropping the future stops the polling.
+    pub async fn wait_for_any_fault(&self, interval: Duration) -> Result<usize> {
+        let mut tick_interval = tokio::time::interval(interval);
+        tick_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        loop {
+            tick_interval.tick().await;
+            let statuses = join_all(self.motors.iter().cloned().map(|motor| async move {
+                motor.get_status().await.map(|status| (motor.id, status))
+            }))
+            .await;
+            for status in statuses {
+                if let (id, Status::Faulted) = status? {
+                    return Ok(id as usize);
+                }
+            }
+        }
+    }
+
+    /// Output states packed as bits, output `n` in bit `n`. The firmware has no bank read, so
+    /// each output is queried separately (back to back); the mask is not an atomic snapshot if
+    /// another task changes outputs meanwhile.
+    pub async fn read_output_mask(&self) -> Result<u32> {
+        let states = join_all(
+            self.outputs
+                .iter()
+                .cloned()
+                .map(|output| async move { output.get_state().await }),
+        )
+        .await;
+        states
+            .into_iter()
+            .enumerate()
+            .try_fold(0, |mask, (idx, state)| Ok(mask | ((state? as u32) << idx)))
+    }
+
+    /// Reports a position-loss event (controller reboot, power glitch) to every motor.
+    pub fn notify_position_lost(&self) {
+        for motor in &self.motors {
+            motor.mark_position_lost();
+        }
+    }
+
+    /// Device name the controller reports for itself (`C0ID`).
+    pub async fn identity(&self) -> Result<String> {
+        let identity_cmd = codec::encode_get_identity(&CONTROLLER_PREFIX);
+        let resp = self.write(identity_cmd.as_slice()).await?;
+        check_reply(&resp)?;
+        Ok(reply_text(&resp)?.to_string())
+    }
+
+    /// Version string of the firmware on the controller (`C0FV`), e.g. `1.4.2`.
+    pub async fn firmware_version(&self) -> Result<String> {
+        let version_cmd = codec::encode_get_firmware_version(&CONTROLLER_PREFIX);
+        let resp = self.write(version_cmd.as_slice()).await?;
+        check_reply(&resp)?;
+        Ok(reply_text(&resp)?.to_string())
+    }
+
+    /// Logs the firmware version and refuses one `ControllerConfig::supported_firmware` does not
+    /// list.
+    async fn check_firmware(&self, config: &ControllerConfig) -> Result<()> {
+        let version = match self.firmware_version().await {
+            Ok(version) => version,
+            Err(e) if config.supported_firmware.is_empty() => {
+                debug!("Controller did not report its firmware version: {e:#}");
+                return Ok(());
+            }
+            Err(e) => return Err(e.context("reading firmware version")),
+        };
+        info!("Controller firmware {version}");
+        if config.supported_firmware.is_empty() || config.supported_firmware.contains(&version) {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "controller firmware {version:?} is not supported, expected one of {:?}",
+                config.supported_firmware
+            ))
+        }
+    }
+
+    /// Errors unless the controller identifies itself as `expected`. Call right after connecting
+    /// to make sure commands go to the intended machine.
+    pub async fn verify_identity(&self, expected: &str) -> Result<()> {
+        let identity = self.identity().await?;
+        if identity == expected {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "connected to controller {identity:?}, expected {expected:?}"
+            ))
+        }
+    }
+
+    /// Supply voltage and power-good flag (`C0PW`, reply `<millivolts>,<good>`); fields the
+    /// firmware leaves empty are `None`. Errors with `ClearCoreError::Unsupported` if the
+    /// firmware does not know the query, and with `ClearCoreError::Rejected` if it refuses it
+    /// for another reason.
+    pub async fn power_status(&self) -> Result<PowerStatus> {
+        let power_cmd = codec::encode_get_power_status(&CONTROLLER_PREFIX);
+        let resp = self.write(power_cmd.as_slice()).await?;
+        check_reply(&resp)?;
+        let mut fields = codec::parse_fields::<f64>(&resp, Layout::Delimited(b','))?.into_iter();
+        let supply_voltage = fields.next().flatten().map(|millivolts| millivolts / 1000.);
+        let power_good = fields.next().flatten().map(|good| good == 1.);
+        Ok(PowerStatus {
+            supply_voltage,
+            power_good,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_of<T: std::fmt::Debug>(result: Result<T>) -> ClearCoreError {
+        result
+            .expect_err("short reply accepted")
+            .downcast::<ClearCoreError>()
+            .expect("not a ClearCoreError")
+    }
+
+    #[test]
+    fn short_replies_are_bad_replies() {
+        let truncated = [STX, b'M', b'0'];
+        for reply in [&[][..], &[STX, CR], &truncated] {
+            let bad = ClearCoreError::BadReply(reply.to_vec());
+            assert_eq!(error_of(check_reply(reply)), bad);
+            assert!(reply_int(reply).is_err());
+        }
+        assert_eq!(
+            error_of(reply_text(&[])),
+            ClearCoreError::BadReply(Vec::new())
+        );
+        // Nothing after the prefix is an empty text, not a malformed one.
+        assert_eq!(reply_text(&truncated).unwrap(), "");
+    }
+
+    #[test]
+    fn rejection_carries_its_code() {
+        let error = error_of(check_reply(b"\x02M0?3\r"));
+        assert!(matches!(
+            error,
+            ClearCoreError::Rejected {
+                code: ControllerErrorCode::MotorNotEnabled,
+                ..
+            }
+        ));
+        assert!(check_reply(b"\x02M0\r").is_ok());
+    }
+
+    /// A controller that answers every frame with `reply(frame)`, connected in memory.
+    fn scripted(reply: fn(&[u8]) -> Vec<u8>) -> ControllerConfig {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+        let transport = Transport::connect(move || async move {
+            let (client, server) = tokio::io::duplex(1024);
+            tokio::spawn(async move {
+                let (read, mut write) = tokio::io::split(server);
+                let mut read = BufReader::new(read);
+                let mut frame = Vec::new();
+                while read.read_until(CR, &mut frame).await.is_ok_and(|n| n > 0) {
+                    if write.write_all(&reply(&frame)).await.is_err() {
+                        break;
+                    }
+                    frame.clear();
+                }
+            });
+            Ok(client)
+        });
+        ControllerConfig::default().with_transport(transport)
+    }
+
+    #[tokio::test]
+    async fn power_status_keeps_other_rejections() {
+        let config = scripted(|frame| match frame {
+            b"\x02C0PW\r" => b"\x02C0?5\r".to_vec(),
+            _ => [&frame[..3], b"0\r"].concat(),
+        });
+        let controller = ControllerHandle::connect("127.0.0.1:0", [], config)
+            .await
+            .unwrap();
+        let error = error_of(controller.power_status().await);
+        assert!(matches!(
+            error,
+            ClearCoreError::Rejected {
+                code: ControllerErrorCode::Busy,
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn motor_ids_above_nine_are_rejected() {
+        let error = ControllerHandle::new("127.0.0.1:0", [MotorBuilder::new(10, 800)])
+            .err()
+            .expect("motor 10 accepted");
+        assert_eq!(
+            error.downcast_ref(),
+            Some(&ClearCoreError::InvalidParameter {
+                parameter: "motor id",
+                value: 10.
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_recovery_keeps_the_enable_error_when_alerts_are_unreadable() {
+        let config = scripted(|frame| match frame {
+            b"\x02M0EN\r" => b"\x02M0?4\r".to_vec(),
+            b"\x02M0AL\r" => b"\x02M0?1\r".to_vec(),
+            _ => [&frame[..3], b"0\r"].concat(),
+        });
+        let controller =
+            ControllerHandle::connect("127.0.0.1:0", [MotorBuilder::new(0, 800)], config)
+                .await
+                .unwrap();
+        let result = controller.get_motor(0).unwrap().recover().await;
+        assert!(matches!(
+            error_of(result),
+            ClearCoreError::Rejected {
+                code: ControllerErrorCode::MotorFaulted,
+                ..
+            }
+        ));
+    }
 }
@@ -0,0 +1,85 @@
+#[cfg(feature = "history")]
+use crate::controller::{FAILED_REPLY, REPLY_IDX};
+#[cfg(feature = "metrics")]
+use std::time::Duration;
+#[cfg(any(feature = "history", feature = "metrics"))]
+use std::{collections::VecDeque, sync::Mutex};
+
+#[cfg(feature = "history")]
+const REJECTION_HISTORY_LEN: usize = 32;
+#[cfg(feature = "metrics")]
+const LATENCY_WINDOW: usize = 256;
+
+/// Round-trip latency over the last few hundred commands, see
+/// `ControllerHandle::latency_stats`.
+#[cfg(feature = "metrics")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LatencyStats {
+    pub samples: usize,
+    pub mean: Duration,
+    pub p50: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+/// Link diagnostics the client task records for the handles that share it.
+#[derive(Debug, Default)]
+pub(crate) struct Diagnostics {
+    #[cfg(feature = "history")]
+    rejections: Mutex<VecDeque<(Vec<u8>, Vec<u8>)>>,
+    #[cfg(feature = "metrics")]
+    latencies: Mutex<VecDeque<Duration>>,
+}
+
+impl Diagnostics {
+    pub fn record_reply(&self, _command: &[u8], _reply: &[u8]) {
+        #[cfg(feature = "history")]
+        if _reply.get(REPLY_IDX) == Some(&FAILED_REPLY) {
+            let mut rejections = self.rejections.lock().expect("rejections poisoned");
+            if rejections.len() == REJECTION_HISTORY_LEN {
+                rejections.pop_front();
+            }
+            rejections.push_back((_command.to_vec(), _reply.to_vec()));
+        }
+    }
+
+    #[cfg(feature = "history")]
+    pub fn recent_rejections(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let rejections = self.rejections.lock().expect("rejections poisoned");
+        rejections.iter().cloned().collect()
+    }
+
+    /// Time from writing a command to reading its reply.
+    #[cfg(feature = "metrics")]
+    pub fn record_latency(&self, latency: Duration) {
+        let mut latencies = self.latencies.lock().expect("latencies poisoned");
+        if latencies.len() == LATENCY_WINDOW {
+            latencies.pop_front();
+        }
+        latencies.push_back(latency);
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn latency_stats(&self) -> Option<LatencyStats> {
+        let mut sorted: Vec<Duration> = self
+            .latencies
+            .lock()
+            .expect("latencies poisoned")
+            .iter()
+            .copied()
+            .collect();
+        if sorted.is_empty() {
+            return None;
+        }
+        sorted.sort_unstable();
+        let samples = sorted.len();
+        let percentile = |p: usize| sorted[(samples * p).div_ceil(100).max(1) - 1];
+        Some(LatencyStats {
+            samples,
+            mean: sorted.iter().sum::<Duration>() / samples as u32,
+            p50: percentile(50),
+            p99: percentile(99),
+            max: sorted[samples - 1],
+        })
+    }
+}
@@ -0,0 +1,142 @@
+use std::sync::Arc;
+use std::time::Duration;
+use std::{fmt, io};
+
+/// Errors callers may want to tell apart; they travel inside `anyhow::Error` and can be
+/// recovered with `downcast_ref::<ClearCoreError>()`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClearCoreError {
+    /// The controller firmware does not implement the named feature, e.g. it rejected one of
+    /// the commands `codec::extension` lists as unknown.
+    Unsupported(&'static str),
+    /// The socket failed while the command was in flight, e.g. `ConnectionReset` or
+    /// `BrokenPipe`. Unlike a reply timeout, the command may not have reached the controller.
+    Transport(io::ErrorKind),
+    /// The controller rejected the command (`?` reply).
+    Rejected {
+        code: ControllerErrorCode,
+        /// The reply frame as text, without the STX and CR.
+        reply: String,
+        /// See `controller::with_context`.
+        context: Option<Arc<str>>,
+    },
+    /// No reply, or not the state waited for, within the time allowed.
+    Timeout(Duration),
+    /// The reply did not echo the command's prefix, so the command or reply was corrupted in
+    /// transit; see `ControllerConfig::verify_echo`. Carries the prefix sent and the one echoed.
+    Corrupted { sent: Vec<u8>, echoed: Vec<u8> },
+    /// The two motors of a `GangedAxis` drifted further apart than allowed.
+    GantrySkew { skew: f64, limit: f64 },
+    /// A reply ran past the configured maximum frame length without a CR terminator.
+    Framing(usize),
+    /// An enable or disable came sooner after the previous one than the motor's minimum cycle
+    /// interval allows; carries how much longer to wait.
+    CyclingTooFast(Duration),
+    /// The client task has stopped, e.g. after `ControllerHandle::shutdown` or a fatal
+    /// connection error, so the command was never sent.
+    Disconnected,
+    /// The device faulted during the operation, or was found faulted.
+    Faulted,
+    /// The client dropped the command without an answer, e.g. because it was shut down while
+    /// the command was in flight, so whether it took effect is unknown. Socket failures are
+    /// `Transport`.
+    ConnectionLost,
+    /// A reply that could not be parsed, e.g. too short or missing a number; carries the frame.
+    BadReply(Vec<u8>),
+    /// A status code this crate does not know.
+    UnknownStatus(u8),
+    /// A move would end outside the motor's soft limits; carries the requested target and the
+    /// limit it crosses, in user units. Nothing was sent.
+    OutOfRange { requested: f64, limit: f64 },
+    /// An argument outside what the command accepts, e.g. a negative velocity; nothing was
+    /// sent.
+    InvalidParameter { parameter: &'static str, value: f64 },
+}
+
+impl fmt::Display for ClearCoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClearCoreError::Unsupported(feature) => {
+                write!(f, "{feature} is not supported by the controller firmware")
+            }
+            ClearCoreError::Transport(kind) => write!(f, "transport error: {kind}"),
+            ClearCoreError::Rejected { reply, context, .. } => {
+                write!(f, "{reply}")?;
+                match context {
+                    Some(context) => write!(f, " (context: {context})"),
+                    None => Ok(()),
+                }
+            }
+            ClearCoreError::Timeout(timeout) => write!(f, "timed out after {timeout:?}"),
+            ClearCoreError::Corrupted { sent, echoed } => {
+                write!(f, "reply echoed {echoed:?} for command {sent:?}")
+            }
+            ClearCoreError::GantrySkew { skew, limit } => {
+                write!(f, "gantry skew {skew} exceeds limit {limit}")
+            }
+            ClearCoreError::Framing(limit) => {
+                write!(f, "reply exceeded {limit} bytes without a terminator")
+            }
+            ClearCoreError::CyclingTooFast(wait) => {
+                write!(f, "enable/disable cycling too fast, retry in {wait:?}")
+            }
+            ClearCoreError::Disconnected => write!(f, "client task has stopped"),
+            ClearCoreError::Faulted => write!(f, "faulted"),
+            ClearCoreError::ConnectionLost => write!(f, "connection lost"),
+            ClearCoreError::BadReply(reply) => {
+                write!(f, "bad reply {:?}", String::from_utf8_lossy(reply))
+            }
+            ClearCoreError::UnknownStatus(code) => write!(f, "unknown status code {code}"),
+            ClearCoreError::OutOfRange { requested, limit } => {
+                write!(f, "target {requested} is beyond soft limit {limit}")
+            }
+            ClearCoreError::InvalidParameter { parameter, value } => {
+                write!(f, "invalid {parameter} {value}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ClearCoreError {}
+
+/// Reason code the firmware puts after the `?` of a rejection. Stock firmware answers a bare
+/// `?`; the numbered codes come from firmware built with the extensions in
+/// `codec::extension`. Any other code is kept as `Other`, with the raw reply in
+/// `ClearCoreError::Rejected`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControllerErrorCode {
+    /// A bare `?` without a code.
+    Unspecified,
+    UnknownCommand,
+    ParameterOutOfRange,
+    MotorNotEnabled,
+    MotorFaulted,
+    /// The device is busy, e.g. a move is already in progress.
+    Busy,
+    /// A code this crate does not know; the raw reply is kept in `ClearCoreError::Rejected`.
+    Other(u32),
+}
+
+impl ControllerErrorCode {
+    /// Parses the digits following the `?`, if any.
+    pub fn parse(code: &[u8]) -> Self {
+        let digits: &[u8] = code
+            .iter()
+            .position(|b| !b.is_ascii_digit())
+            .map_or(code, |end| &code[..end]);
+        let Some(code) = std::str::from_utf8(digits)
+            .ok()
+            .and_then(|digits| digits.parse().ok())
+        else {
+            return ControllerErrorCode::Unspecified;
+        };
+        match code {
+            1 => ControllerErrorCode::UnknownCommand,
+            2 => ControllerErrorCode::ParameterOutOfRange,
+            3 => ControllerErrorCode::MotorNotEnabled,
+            4 => ControllerErrorCode::MotorFaulted,
+            5 => ControllerErrorCode::Busy,
+            code => ControllerErrorCode::Other(code),
+        }
+    }
+}
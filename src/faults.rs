@@ -0,0 +1,67 @@
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tokio::time::Instant;
+
+/// Capacity of the fault bus; a subscriber that falls further behind sees `Lagged`.
+pub(crate) const FAULT_EVENT_CAPACITY: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Device {
+    Motor(u8),
+    HBridge(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum FaultKind {
+    /// The motor reported `Status::Faulted`.
+    MotorFaulted,
+    Overcurrent,
+    Overtemperature,
+    /// The motor reported `Status::Moving` without a command from this client to explain it,
+    /// see `ClearCoreMotor::set_unexpected_motion_check`.
+    UnexpectedMotion,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FaultEvent {
+    pub device: Device,
+    pub kind: FaultKind,
+    pub timestamp: Instant,
+}
+
+/// Publishes a device's faults to the controller's fault bus, once per change into a fault
+/// rather than on every read that sees it.
+#[derive(Clone, Debug)]
+pub(crate) struct FaultReporter {
+    events: broadcast::Sender<FaultEvent>,
+    device: Device,
+    current: Arc<Mutex<Option<FaultKind>>>,
+}
+
+impl FaultReporter {
+    pub fn new(events: broadcast::Sender<FaultEvent>, device: Device) -> Self {
+        Self {
+            events,
+            device,
+            current: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Records the fault state a read found, `None` when healthy.
+    pub fn update(&self, fault: Option<FaultKind>) {
+        let previous = std::mem::replace(
+            &mut *self.current.lock().expect("fault state poisoned"),
+            fault,
+        );
+        if let Some(kind) = fault.filter(|kind| previous != Some(*kind)) {
+            // No subscribers is not an error.
+            let _ = self.events.send(FaultEvent {
+                device: self.device,
+                kind,
+                timestamp: Instant::now(),
+            });
+        }
+    }
+}
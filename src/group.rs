@@ -0,0 +1,284 @@
+use std::future::Future;
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow};
+use log::error;
+use tokio::time::MissedTickBehavior;
+
+use crate::controller::collect_errors;
+use crate::error::ClearCoreError;
+use crate::join_all;
+use crate::motor::{ClearCoreMotor, Status};
+
+/// How a group sends one command to each of its motors.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Issuance {
+    /// All commands are sent back to back and the replies awaited together, so the motors start
+    /// within one round trip of each other. The order they reach the controller is unspecified.
+    #[default]
+    Concurrent,
+    /// Commands are sent in group order, each only after the previous one was accepted. Takes
+    /// one round trip per motor, and stops at the first rejection.
+    Ordered,
+}
+
+/// Several motors commanded together.
+#[derive(Clone)]
+pub struct MotorGroup {
+    motors: Vec<ClearCoreMotor>,
+    issuance: Issuance,
+}
+
+impl MotorGroup {
+    pub fn new(motors: Vec<ClearCoreMotor>) -> Self {
+        Self {
+            motors,
+            issuance: Issuance::default(),
+        }
+    }
+
+    pub fn with_issuance(mut self, issuance: Issuance) -> Self {
+        self.issuance = issuance;
+        self
+    }
+
+    pub fn motors(&self) -> &[ClearCoreMotor] {
+        &self.motors
+    }
+
+    /// Runs `command` for each motor paired with its argument, as set by `with_issuance`.
+    async fn issue<T, F, Fut>(&self, args: Vec<(ClearCoreMotor, T)>, command: F) -> Result<()>
+    where
+        F: Fn(ClearCoreMotor, T) -> Fut,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let commands = args.into_iter().map(|(motor, arg)| {
+            let id = motor.id;
+            let command = command(motor, arg);
+            async move { command.await.with_context(|| format!("motor {id}")) }
+        });
+        match self.issuance {
+            Issuance::Concurrent => collect_errors(join_all(commands).await),
+            Issuance::Ordered => {
+                for command in commands {
+                    command.await?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn check_len(&self, args: &[f64], what: &str) -> Result<()> {
+        if args.len() != self.motors.len() {
+            return Err(anyhow!(
+                "expected {} {what}, got {}",
+                self.motors.len(),
+                args.len()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Moves each motor to its entry in `targets`; does not wait for the moves to finish.
+    pub async fn absolute_move_all(&self, targets: &[f64]) -> Result<()> {
+        self.check_len(targets, "targets")?;
+        let args = self.motors.iter().cloned().zip(targets.iter().copied());
+        self.issue(args.collect(), |motor, target| async move {
+            motor.absolute_move(target).await
+        })
+        .await
+    }
+
+    pub async fn enable_all(&self) -> Result<()> {
+        let args = self.motors.iter().cloned().map(|motor| (motor, ()));
+        self.issue(
+            args.collect(),
+            |motor, ()| async move { motor.enable().await },
+        )
+        .await
+    }
+
+    /// Straight-line move to `targets` (one per motor) at `feedrate` user units per second along
+    /// the path. Each axis's velocity is set proportional to its share of the distance so all
+    /// axes arrive together, then the moves are issued; this does not wait for them to finish.
+    ///
+    /// There is no interpolation on the controller: the path is only as straight as the axes'
+    /// velocities (and their acceleration ramps) match. Axes that are already at their target
+    /// are left alone.
+    pub async fn linear_move(&self, targets: &[f64], feedrate: f64) -> Result<()> {
+        self.check_len(targets, "targets")?;
+        let positions = self.positions().await?;
+        self.linear_move_from(&positions, targets, feedrate).await
+    }
+
+    /// Position of each motor, in group order.
+    pub(crate) async fn positions(&self) -> Result<Vec<f64>> {
+        join_all(
+            self.motors
+                .iter()
+                .cloned()
+                .map(|motor| async move { motor.get_position().await }),
+        )
+        .await
+        .into_iter()
+        .collect()
+    }
+
+    /// `linear_move` from `positions` already read by the caller.
+    pub(crate) async fn linear_move_from(
+        &self,
+        positions: &[f64],
+        targets: &[f64],
+        feedrate: f64,
+    ) -> Result<()> {
+        let distances: Vec<f64> = targets
+            .iter()
+            .zip(positions)
+            .map(|(target, position)| target - position)
+            .collect();
+        let path_length = distances.iter().map(|d| d * d).sum::<f64>().sqrt();
+        if path_length == 0. {
+            return Ok(());
+        }
+        let moving: Vec<(ClearCoreMotor, f64, f64)> = self
+            .motors
+            .iter()
+            .cloned()
+            .zip(targets.iter().copied())
+            .zip(distances)
+            .filter(|(_, distance)| *distance != 0.)
+            .map(|((motor, target), distance)| {
+                (motor, target, feedrate * distance.abs() / path_length)
+            })
+            .collect();
+        let velocities = moving
+            .iter()
+            .map(|(motor, _, velocity)| (motor.clone(), *velocity));
+        self.issue(velocities.collect(), |motor, velocity| async move {
+            motor.set_velocity(velocity).await
+        })
+        .await?;
+        let targets = moving.into_iter().map(|(motor, target, _)| (motor, target));
+        self.issue(targets.collect(), |motor, target| async move {
+            motor.absolute_move(target).await
+        })
+        .await
+    }
+}
+
+/// Two motors driving one mechanical axis, e.g. both sides of a gantry. Every move goes to both,
+/// and the difference between their positions is watched while it runs.
+#[derive(Clone)]
+pub struct GangedAxis {
+    motors: MotorGroup,
+    max_skew: f64,
+}
+
+impl GangedAxis {
+    /// `max_skew` is the largest position difference, in user units, tolerated between the two.
+    pub fn new(primary: ClearCoreMotor, secondary: ClearCoreMotor, max_skew: f64) -> Self {
+        Self {
+            motors: MotorGroup::new(vec![primary, secondary]),
+            max_skew,
+        }
+    }
+
+    /// Position of the primary minus that of the secondary.
+    pub async fn skew(&self) -> Result<f64> {
+        let [primary, secondary] = self
+            .read(|motor| async move { motor.get_position().await })
+            .await?;
+        Ok(primary - secondary)
+    }
+
+    /// Moves both motors to `position` and waits for them to finish, checking the skew and
+    /// status every `poll`. If the skew exceeds the limit the move fails with
+    /// `ClearCoreError::GantrySkew`, if either motor faults with `ClearCoreError::Faulted`. On
+    /// any error both motors are stopped abruptly, so neither side is left driving alone.
+    pub async fn absolute_move(&self, position: f64, poll: Duration) -> Result<()> {
+        let result = async {
+            self.check_skew().await?;
+            self.motors.absolute_move_all(&[position, position]).await?;
+            self.watch(poll).await
+        }
+        .await;
+        self.stop_on_error(result).await
+    }
+
+    /// Moves both motors by `distance`, see `absolute_move`.
+    pub async fn relative_move(&self, distance: f64, poll: Duration) -> Result<()> {
+        let result = async {
+            self.check_skew().await?;
+            let args = self
+                .motors
+                .motors
+                .iter()
+                .cloned()
+                .map(|motor| (motor, distance));
+            self.motors
+                .issue(args.collect(), |motor, distance| async move {
+                    motor.relative_move(distance).await
+                })
+                .await?;
+            self.watch(poll).await
+        }
+        .await;
+        self.stop_on_error(result).await
+    }
+
+    async fn read<T, F, Fut>(&self, query: F) -> Result<[T; 2]>
+    where
+        F: Fn(ClearCoreMotor) -> Fut,
+        Fut: Future<Output = Result<T>> + Send + 'static,
+        T: Send + 'static,
+    {
+        let results = join_all(self.motors.motors.iter().cloned().map(query)).await;
+        let [primary, secondary]: [Result<T>; 2] = results
+            .try_into()
+            .map_err(|_| anyhow!("ganged axis has two motors"))?;
+        Ok([primary?, secondary?])
+    }
+
+    async fn check_skew(&self) -> Result<()> {
+        let skew = self.skew().await?;
+        if skew.abs() > self.max_skew {
+            return Err(ClearCoreError::GantrySkew {
+                skew,
+                limit: self.max_skew,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    async fn stop_on_error(&self, result: Result<()>) -> Result<()> {
+        if result.is_err() {
+            let stops = self.read(|motor| async move { motor.abrupt_stop().await });
+            if let Err(e) = stops.await {
+                error!("Failed to stop ganged axis: {e}");
+            }
+        }
+        result
+    }
+
+    async fn watch(&self, poll: Duration) -> Result<()> {
+        let mut tick_interval = tokio::time::interval(poll);
+        tick_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        loop {
+            tick_interval.tick().await;
+            self.check_skew().await?;
+            let statuses = self
+                .read(|motor| async move { motor.get_status().await })
+                .await?;
+            for (motor, status) in self.motors.motors.iter().zip(statuses) {
+                if status == Status::Faulted {
+                    return Err(ClearCoreError::Faulted)
+                        .with_context(|| format!("ganged motor {}", motor.id));
+                }
+            }
+            if !statuses.contains(&Status::Moving) {
+                return self.check_skew().await;
+            }
+        }
+    }
+}
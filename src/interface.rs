@@ -1,35 +1,578 @@
-use crate::controller::Message;
-use log::{error, info};
+use crate::codec::CommandKind;
+use crate::controller::{
+    ByteStream, CR, ConnectionState, ControllerConfig, Message, Priority, REPLY_LEN, Transport,
+    check_reply,
+};
+use crate::diagnostics::Diagnostics;
+use crate::error::ClearCoreError;
+use log::{error, info, warn};
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
 use std::error::Error;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::ops::ControlFlow;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpStream, ToSocketAddrs};
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
+use tokio::time::MissedTickBehavior;
+
+const HANDSHAKE_RETRY: Duration = Duration::from_millis(250);
+/// Replies start with the command's `STX, device, id`.
+const ECHO_LEN: usize = 3;
 
 pub async fn client<T: ToSocketAddrs>(
     addr: T,
-    mut msg: mpsc::Receiver<Message>,
+    msg: mpsc::Receiver<Message>,
+    mut sections: mpsc::Receiver<mpsc::Receiver<Message>>,
+    mut stop: watch::Receiver<bool>,
+    config: ControllerConfig,
+    state: watch::Sender<ConnectionState>,
+    diagnostics: Arc<Diagnostics>,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let mut stream = TcpStream::connect(addr).await?;
-    let peer_addr = stream.peer_addr().expect(" Peer not connected");
-    info!("Client connected with peer address: {peer_addr}");
+    let mut msg = Queue::new(msg);
+    let mut attempt = 0;
+    let result = loop {
+        let result = session(
+            &addr,
+            &mut msg,
+            &mut sections,
+            &mut stop,
+            &config,
+            &state,
+            &diagnostics,
+        )
+        .await;
+        let (Err(e), Some(reconnect)) = (&result, &config.reconnect) else {
+            break result;
+        };
+        if *state.borrow() == ConnectionState::Ready {
+            attempt = 0;
+        }
+        let delay = reconnect.delay(attempt);
+        attempt += 1;
+        error!("Connection to controller lost: {e}; reconnecting in {delay:?}");
+        state.send_replace(ConnectionState::Reconnecting);
+        if reject_while_disconnected(&mut msg, &mut sections, &mut stop, delay)
+            .await
+            .is_break()
+        {
+            break Ok(());
+        }
+        state.send_replace(ConnectionState::Connecting);
+    };
+    state.send_replace(match result {
+        Ok(()) => ConnectionState::Closed,
+        Err(_) => ConnectionState::Failed,
+    });
+    // Anything still queued after a shutdown will never be sent.
+    msg.close();
     while let Some(message) = msg.recv().await {
-        stream.write_all(&message.buffer).await?;
-        stream.readable().await?;
-        let mut buffer = [0; 100];
-        match stream.read(&mut buffer).await {
-            Ok(0) => {
-                error!("Connection closed by server");
-            }
-            Ok(_) => {
-                if message.response.send(buffer.to_vec()).is_err() {
-                    error!("Failed to send via channel");
+        let _ = message.response.send(not_connected());
+    }
+    result
+}
+
+/// Resolves once a shutdown is requested; errors if the handles are gone without one.
+async fn stop_requested(stop: &mut watch::Receiver<bool>) -> Result<(), watch::error::RecvError> {
+    stop.wait_for(|stop| *stop).await.map(|_| ())
+}
+
+fn not_connected() -> Result<Vec<u8>, ClearCoreError> {
+    Err(ClearCoreError::Transport(std::io::ErrorKind::NotConnected))
+}
+
+/// Fails every command sent during `delay` instead of leaving it queued for a link that is not
+/// there. Breaks if every handle is gone or a shutdown was requested.
+async fn reject_while_disconnected(
+    msg: &mut Queue,
+    sections: &mut mpsc::Receiver<mpsc::Receiver<Message>>,
+    stop: &mut watch::Receiver<bool>,
+    delay: Duration,
+) -> ControlFlow<()> {
+    let sleep = tokio::time::sleep(delay);
+    tokio::pin!(sleep);
+    loop {
+        tokio::select! {
+            _ = &mut sleep => return ControlFlow::Continue(()),
+            Ok(()) = stop_requested(stop) => return ControlFlow::Break(()),
+            Some(mut section) = sections.recv() => {
+                section.close();
+                while let Some(message) = section.recv().await {
+                    let _ = message.response.send(not_connected());
                 }
             }
-            Err(e) => {
-                error!("Failed to read from stream: {}", e);
-                break;
+            message = msg.recv() => match message {
+                Some(message) => {
+                    let _ = message.response.send(not_connected());
+                }
+                None => return ControlFlow::Break(()),
+            },
+        }
+    }
+}
+
+/// Serves commands over one connection. Returns `Ok` once every handle is gone or a shutdown
+/// was requested, and an error when the connection fails. A shutdown lets the exchange in
+/// flight finish; the connection closes when the stream is dropped.
+async fn session<T: ToSocketAddrs>(
+    addr: &T,
+    msg: &mut Queue,
+    sections: &mut mpsc::Receiver<mpsc::Receiver<Message>>,
+    stop: &mut watch::Receiver<bool>,
+    config: &ControllerConfig,
+    state: &watch::Sender<ConnectionState>,
+    diagnostics: &Diagnostics,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let connect = async {
+        let mut stream: Box<dyn ByteStream> = match &config.transport {
+            Transport::Connect(connect) => connect().await?,
+            transport => {
+                let stream = TcpStream::connect(addr).await?;
+                let peer_addr = stream.peer_addr().expect(" Peer not connected");
+                info!("Client connected with peer address: {peer_addr}");
+                match transport {
+                    Transport::Wrapped(wrap) => wrap(stream).await?,
+                    #[cfg(feature = "tls")]
+                    Transport::Tls(tls, server_name) => {
+                        let connector = tokio_rustls::TlsConnector::from(tls.clone());
+                        Box::new(connector.connect(server_name.clone(), stream).await?)
+                    }
+                    _ => Box::new(stream),
+                }
             }
+        };
+        if let Some(query) = &config.handshake {
+            state.send_replace(ConnectionState::Handshaking);
+            handshake(&mut stream, query, config).await?;
+        }
+        Ok::<_, Box<dyn Error + Send + Sync>>(stream)
+    };
+    let stream = tokio::select! {
+        stream = connect => stream?,
+        Ok(()) = stop_requested(stop) => return Ok(()),
+    };
+    state.send_replace(ConnectionState::Ready);
+    let mut link = Link {
+        stream,
+        pending: Vec::new(),
+        config,
+        diagnostics,
+    };
+    let mut sections_open = true;
+    loop {
+        tokio::select! {
+            section = sections.recv(), if sections_open => match section {
+                // A critical section has the link to itself until every sender to it is gone.
+                Some(mut section) => {
+                    while let Some(message) = section.recv().await {
+                        link.exchange(message).await?;
+                    }
+                }
+                None => sections_open = false,
+            },
+            message = msg.recv() => match message {
+                Some(message) => link.exchange(message).await?,
+                None => return Ok(()),
+            },
+            Ok(()) = stop_requested(stop) => return Ok(()),
+        }
+    }
+}
+
+/// The command channel, reordered by `Message::priority`: everything already waiting in the
+/// channel is moved into a heap and the highest priority served first.
+struct Queue {
+    rx: mpsc::Receiver<Message>,
+    heap: BinaryHeap<Queued>,
+    seq: u64,
+}
+
+struct Queued {
+    priority: Priority,
+    seq: Reverse<u64>,
+    message: Message,
+}
+
+impl PartialEq for Queued {
+    fn eq(&self, other: &Self) -> bool {
+        (self.priority, self.seq) == (other.priority, other.seq)
+    }
+}
+
+impl Eq for Queued {}
+
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Queued {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.priority, self.seq).cmp(&(other.priority, other.seq))
+    }
+}
+
+impl Queue {
+    fn new(rx: mpsc::Receiver<Message>) -> Self {
+        Self {
+            rx,
+            heap: BinaryHeap::new(),
+            seq: 0,
+        }
+    }
+
+    fn push(&mut self, message: Message) {
+        self.seq += 1;
+        self.heap.push(Queued {
+            priority: message.priority,
+            seq: Reverse(self.seq),
+            message,
+        });
+    }
+
+    /// Next command to send; `None` once every sender is gone and the queue is empty.
+    /// Cancel safe.
+    async fn recv(&mut self) -> Option<Message> {
+        while let Ok(message) = self.rx.try_recv() {
+            self.push(message);
         }
+        match self.heap.pop() {
+            Some(queued) => Some(queued.message),
+            None => self.rx.recv().await,
+        }
+    }
+
+    fn close(&mut self) {
+        self.rx.close();
+    }
+}
+
+struct Link<'a> {
+    stream: Box<dyn ByteStream>,
+    /// Bytes read past the end of the last reply, see `read_frame`.
+    pending: Vec<u8>,
+    config: &'a ControllerConfig,
+    diagnostics: &'a Diagnostics,
+}
+
+impl Link<'_> {
+    /// Sends one command and hands its reply, or the reason there is none, to the sender. Errors
+    /// when the connection is no longer usable, including when a reply is missing, overlong or
+    /// does not echo the command: the stream can then no longer be trusted to line replies up
+    /// with commands, as a late reply would be taken for the next command's, so the session
+    /// ends and the client reconnects.
+    async fn exchange(&mut self, message: Message) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let config = self.config;
+        let context = message
+            .context
+            .as_ref()
+            .map(|context| format!(" (context: {context})"))
+            .unwrap_or_default();
+        let kind = CommandKind::of(&message.buffer);
+        let timeout = config.timeouts.for_kind(kind);
+        #[cfg(feature = "metrics")]
+        let written = tokio::time::Instant::now();
+        if let Err(e) = self.stream.write_all(&message.buffer).await {
+            error!("Failed to write to stream: {e}{context}");
+            let _ = message
+                .response
+                .send(Err(ClearCoreError::Transport(e.kind())));
+            return Err(e.into());
+        }
+        let reply = tokio::time::timeout(
+            timeout,
+            read_frame(&mut self.stream, &mut self.pending, config.max_frame_len),
+        )
+        .await;
+        match reply {
+            Err(_) => {
+                error!("No reply within {timeout:?} to {kind:?} command{context}");
+                let _ = message.response.send(Err(ClearCoreError::Timeout(timeout)));
+                return Err(format!("no reply within {timeout:?}").into());
+            }
+            Ok(Ok(Frame::Closed)) => {
+                error!("Connection closed by server{context}");
+                let error = ClearCoreError::Transport(std::io::ErrorKind::UnexpectedEof);
+                let _ = message.response.send(Err(error));
+                return Err("connection closed by server".into());
+            }
+            Ok(Ok(Frame::TooLong)) => {
+                error!(
+                    "Reply exceeded {} bytes without a terminator{context}",
+                    config.max_frame_len
+                );
+                let error = ClearCoreError::Framing(config.max_frame_len);
+                let _ = message.response.send(Err(error.clone()));
+                return Err(error.into());
+            }
+            Ok(Ok(Frame::Complete(frame)))
+                if config.verify_echo
+                    && frame.get(..ECHO_LEN) != message.buffer.get(..ECHO_LEN) =>
+            {
+                warn!("Reply {frame:?} does not echo the command{context}");
+                let error = ClearCoreError::Corrupted {
+                    sent: message.buffer.iter().take(ECHO_LEN).copied().collect(),
+                    echoed: frame.iter().take(ECHO_LEN).copied().collect(),
+                };
+                let _ = message.response.send(Err(error.clone()));
+                return Err(error.into());
+            }
+            Ok(Ok(Frame::Complete(mut frame))) => {
+                #[cfg(feature = "metrics")]
+                self.diagnostics.record_latency(written.elapsed());
+                self.diagnostics.record_reply(&message.buffer, &frame);
+                if frame.len() < REPLY_LEN {
+                    frame.resize(REPLY_LEN, 0);
+                }
+                if message.response.send(Ok(frame)).is_err() {
+                    error!("Failed to send via channel{context}");
+                }
+            }
+            Ok(Err(e)) => {
+                error!("Failed to read from stream: {}{context}", e);
+                let _ = message
+                    .response
+                    .send(Err(ClearCoreError::Transport(e.kind())));
+                return Err(e.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+enum Frame {
+    Complete(Vec<u8>),
+    Closed,
+    TooLong,
+}
+
+/// Takes one CR-terminated reply off `pending`, reading from `stream` until one is there, and
+/// gives up once `max_len` bytes arrive without a CR. Bytes after the CR stay in `pending` for
+/// the next call, e.g. when two replies arrive in one segment. Cancel safe: whatever was read
+/// is kept in `pending`.
+async fn read_frame(
+    stream: &mut (impl AsyncRead + Unpin),
+    pending: &mut Vec<u8>,
+    max_len: usize,
+) -> std::io::Result<Frame> {
+    let mut buffer = [0; REPLY_LEN];
+    let mut scanned = 0;
+    loop {
+        if let Some(end) = pending[scanned..].iter().position(|&byte| byte == CR) {
+            let end = scanned + end + 1;
+            if end > max_len {
+                pending.clear();
+                return Ok(Frame::TooLong);
+            }
+            return Ok(Frame::Complete(pending.drain(..end).collect()));
+        }
+        if pending.len() > max_len {
+            pending.clear();
+            return Ok(Frame::TooLong);
+        }
+        scanned = pending.len();
+        match stream.read(&mut buffer).await? {
+            0 => return Ok(Frame::Closed),
+            n => pending.extend_from_slice(&buffer[..n]),
+        }
+    }
+}
+
+/// Repeats `query` until the controller answers it without rejecting it. Errors if a reply
+/// does not arrive within the query timeout, so a silent controller fails the connection attempt
+/// rather than holding it open.
+async fn handshake(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    query: &[u8],
+    config: &ControllerConfig,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let timeout = config.timeouts.query;
+    // Anything left over once the controller is ready answers an earlier retry; drop it.
+    let mut pending = Vec::new();
+    let mut tick_interval = tokio::time::interval(HANDSHAKE_RETRY);
+    tick_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    loop {
+        tick_interval.tick().await;
+        stream.write_all(query).await?;
+        let reply = tokio::time::timeout(
+            timeout,
+            read_frame(stream, &mut pending, config.max_frame_len),
+        )
+        .await
+        .map_err(|_| ClearCoreError::Timeout(timeout))?;
+        match reply? {
+            Frame::Complete(frame) => match check_reply(&frame) {
+                Ok(()) => return Ok(()),
+                Err(e) => warn!("Controller not ready: {e}"),
+            },
+            Frame::Closed => return Err("Connection closed by server during handshake".into()),
+            Frame::TooLong => return Err(ClearCoreError::Framing(config.max_frame_len).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::{CommandTimeouts, ControllerHandle, ReconnectPolicy};
+    use tokio::net::TcpListener;
+
+    const QUERY: &[u8] = b"\x02C0ID\r";
+
+    /// Accepts one connection and hands it to `serve`.
+    async fn serve_once<F, Fut>(serve: F) -> std::net::SocketAddr
+    where
+        F: FnOnce(TcpStream) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            serve(stream).await;
+        });
+        addr
+    }
+
+    fn handshake_config() -> ControllerConfig {
+        ControllerConfig::default()
+            .with_handshake(QUERY)
+            .with_reconnect(None)
+    }
+
+    #[tokio::test]
+    async fn silent_controller_fails_handshake() {
+        let addr = serve_once(|stream| async move {
+            std::future::pending::<()>().await;
+            drop(stream);
+        })
+        .await;
+        let controller = ControllerHandle::with_config(addr, [], handshake_config()).unwrap();
+        let ready = tokio::time::timeout(Duration::from_secs(5), controller.wait_ready())
+            .await
+            .expect("handshake hung");
+        assert!(ready.is_err());
+    }
+
+    #[tokio::test]
+    async fn handshake_reply_split_across_segments() {
+        let queries = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let answered = queries.clone();
+        let addr = serve_once(|mut stream| async move {
+            let mut query = [0; QUERY.len()];
+            // A rejection split in two, which must not pass for an answer, then a good reply.
+            stream.read_exact(&mut query).await.unwrap();
+            answered.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            stream.write_all(b"\x02C0").await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            stream.write_all(b"?1\r").await.unwrap();
+            stream.read_exact(&mut query).await.unwrap();
+            answered.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            stream.write_all(b"\x02C0MOCK\r").await.unwrap();
+            std::future::pending::<()>().await;
+        })
+        .await;
+        let controller = ControllerHandle::with_config(addr, [], handshake_config()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), controller.wait_ready())
+            .await
+            .expect("handshake hung")
+            .unwrap();
+        assert_eq!(queries.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn late_reply_is_not_taken_for_the_next_command() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut query = [0; QUERY.len()];
+            // The first connection answers only after the query timed out.
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.read_exact(&mut query).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            let _ = stream.write_all(b"\x02C0LATE\r").await;
+            let (mut stream, _) = listener.accept().await.unwrap();
+            while stream.read_exact(&mut query).await.is_ok() {
+                stream.write_all(b"\x02C0MOCK\r").await.unwrap();
+            }
+        });
+        let config = ControllerConfig::default()
+            .with_timeouts(CommandTimeouts {
+                query: Duration::from_millis(100),
+                ..Default::default()
+            })
+            .with_reconnect(Some(ReconnectPolicy {
+                base_delay: Duration::from_millis(10),
+                max_delay: Duration::from_millis(10),
+            }));
+        let controller = ControllerHandle::with_config(addr, [], config).unwrap();
+        let error = controller
+            .identity()
+            .await
+            .expect_err("late reply accepted");
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(ClearCoreError::Timeout(_))
+        ));
+        let identity = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Ok(identity) = controller.identity().await {
+                    break identity;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("client did not reconnect");
+        assert_eq!(identity, "MOCK");
+    }
+
+    #[tokio::test]
+    async fn replies_sharing_a_segment_are_both_read() {
+        let mut stream = &b"\x02M0GP100\r\x02M0GS3\r\x02M0"[..];
+        let mut pending = Vec::new();
+        let mut next = async || read_frame(&mut stream, &mut pending, 1024).await.unwrap();
+        assert!(matches!(next().await, Frame::Complete(frame) if frame == b"\x02M0GP100\r"));
+        assert!(matches!(next().await, Frame::Complete(frame) if frame == b"\x02M0GS3\r"));
+        assert!(matches!(next().await, Frame::Closed));
+        assert_eq!(pending, b"\x02M0");
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn tls_transport_speaks_the_protocol_over_tls() {
+        use tokio_rustls::rustls::{self, pki_types::ServerName};
+        let cert = rcgen::generate_simple_self_signed(["localhost".to_string()]).unwrap();
+        let key =
+            rustls::pki_types::PrivateKeyDer::try_from(cert.key_pair.serialize_der()).unwrap();
+        let server = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.cert.der().clone()], key)
+            .unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server));
+        let addr = serve_once(|stream| async move {
+            let mut stream = acceptor.accept(stream).await.unwrap();
+            let mut query = [0; QUERY.len()];
+            while stream.read_exact(&mut query).await.is_ok() {
+                stream.write_all(b"\x02C0MOCK\r").await.unwrap();
+            }
+        })
+        .await;
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert.cert.der().clone()).unwrap();
+        let client = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let config =
+            handshake_config().with_transport(Transport::Tls(Arc::new(client), server_name));
+        let controller = ControllerHandle::with_config(addr, [], config).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), controller.wait_ready())
+            .await
+            .expect("handshake hung")
+            .unwrap();
+        assert_eq!(controller.identity().await.unwrap(), "MOCK");
     }
-    Ok(())
 }
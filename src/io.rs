@@ -1,27 +1,173 @@
-use crate::controller::{CR, Message, STX, check_reply};
+use crate::codec::{self, Layout};
+use crate::controller::{
+    CR, FAILED_REPLY, Message, REPLY_IDX, STX, check_reply, make_prefix, reply_int,
+};
+use crate::error::ClearCoreError;
+use crate::faults::{FaultKind, FaultReporter};
+use crate::int_to_byte;
 use crate::send_recv::SendRecv;
-use crate::{ascii_to_int, int_to_byte, num_to_bytes};
-use tokio::sync::mpsc::Sender;
+use log::{error, warn};
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::{self, Sender};
+use tokio::time::{Instant, MissedTickBehavior};
 
-use anyhow::Result;
+use anyhow::{Result, anyhow};
 
 pub const CLEAR_CORE_H_BRIDGE_MAX: i16 = 32760;
+/// Value a digital output is driven with to turn it on.
+const OUTPUT_HIGH: isize = 32700;
+/// The controller samples inputs at 5 kHz; filter lengths are expressed in samples.
+const INPUT_SAMPLE_PERIOD: Duration = Duration::from_micros(200);
+const MAX_FILTER_SAMPLES: u128 = u16::MAX as u128;
+/// ClearCore analog inputs default to 12-bit readings over 0-10 V.
+pub const DEFAULT_ADC_RESOLUTION: u32 = 4095;
+pub const DEFAULT_ADC_SPAN: f64 = 10.;
+
+/// Electrical level at which the sensor is energized (high for pull-down wiring, low for
+/// pull-up wiring).
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum Level {
+    High,
+    Low,
+}
+
+/// Contact type of the device wired to the input.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum Normally {
+    Open,
+    Closed,
+}
+
 #[derive(Clone)]
 pub struct DigitalInput {
     cmd: [u8; 4],
+    active_level: Level,
+    normally: Normally,
+    debounce: Duration,
     drive_sender: Sender<Message>,
 }
 
+/// Level transition `wait_for_edge` waits for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Edge {
+    Rising,
+    Falling,
+    Any,
+}
+
 impl DigitalInput {
     pub fn new(id: u8, drive_sender: Sender<Message>) -> Self {
         let cmd = [STX, b'I', int_to_byte(id), CR];
-        Self { cmd, drive_sender }
+        Self {
+            cmd,
+            active_level: Level::High,
+            normally: Normally::Open,
+            debounce: Duration::ZERO,
+            drive_sender,
+        }
+    }
+
+    pub fn with_wiring(mut self, active_level: Level, normally: Normally) -> Self {
+        self.active_level = active_level;
+        self.normally = normally;
+        self
+    }
+
+    /// Time a new level must hold across polls before `wait_for_edge` reports it, for
+    /// mechanical switches. Unlike `set_debounce` this is applied by the client, on top of the
+    /// controller's filter. Zero by default.
+    pub fn with_debounce(mut self, debounce: Duration) -> Self {
+        self.debounce = debounce;
+        self
     }
 
     pub async fn get_state(&self) -> Result<bool> {
-        let resp = self.write(self.cmd.as_slice()).await;
+        let resp = self.write(self.cmd.as_slice()).await?;
+        Ok(reply_int(&resp)? == 1)
+    }
+
+    /// Same as `get_state`: `true` when the input reads high.
+    pub async fn read(&self) -> Result<bool> {
+        self.get_state().await
+    }
+
+    /// Polls every `poll` until the input changes level in the direction of `edge` and the new
+    /// level holds for the debounce time. A level already present when called is not an edge.
+    pub async fn wait_for_edge(&self, edge: Edge, poll: Duration) -> Result<()> {
+        let level = self.read().await?;
+        self.wait_for_edge_from(level, edge, poll).await
+    }
+
+    /// `wait_for_edge` measured from `level`, read by the caller before starting whatever is
+    /// expected to cause the edge, so an edge coming before the first poll is not missed.
+    pub(crate) async fn wait_for_edge_from(
+        &self,
+        mut level: bool,
+        edge: Edge,
+        poll: Duration,
+    ) -> Result<()> {
+        let mut tick_interval = tokio::time::interval(poll);
+        tick_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        tick_interval.tick().await;
+        loop {
+            tick_interval.tick().await;
+            let new_level = self.read().await?;
+            if new_level == level {
+                continue;
+            }
+            let changed_at = Instant::now();
+            let mut stable = true;
+            while changed_at.elapsed() < self.debounce {
+                tick_interval.tick().await;
+                if self.read().await? != new_level {
+                    stable = false;
+                    break;
+                }
+            }
+            if !stable {
+                continue;
+            }
+            level = new_level;
+            match edge {
+                Edge::Rising if level => return Ok(()),
+                Edge::Falling if !level => return Ok(()),
+                Edge::Any => return Ok(()),
+                _ => {}
+            }
+        }
+    }
+
+    /// Configures the controller's input filter, which requires the input to hold a new level
+    /// for `duration` before reporting it. Rounded to the nearest 200 µs sample period, halves
+    /// up; at most ~13.1 s.
+    pub async fn set_debounce(&self, duration: Duration) -> Result<()> {
+        let period = INPUT_SAMPLE_PERIOD.as_micros();
+        let samples = (duration.as_micros() + period / 2) / period;
+        if samples > MAX_FILTER_SAMPLES {
+            return Err(anyhow!(
+                "debounce {duration:?} exceeds the controller maximum of {:?}",
+                INPUT_SAMPLE_PERIOD * MAX_FILTER_SAMPLES as u32
+            ));
+        }
+        let cmd = codec::encode_set_input_filter(&self.cmd[..3], samples as isize);
+        let resp = self.write(&cmd).await?;
         check_reply(&resp)?;
-        Ok(ascii_to_int(&resp[3..]) == 1)
+        Ok(())
+    }
+
+    pub async fn get_debounce(&self) -> Result<Duration> {
+        let get_filter_cmd = codec::encode_get_input_filter(&self.cmd[..3]);
+        let resp = self.write(get_filter_cmd.as_slice()).await?;
+        Ok(INPUT_SAMPLE_PERIOD * reply_int(&resp)? as u32)
+    }
+
+    /// Logical state after applying the wiring: for a normally-closed device the input is
+    /// active when the circuit is open, so a broken wire reads as active (tripped).
+    pub async fn is_active(&self) -> Result<bool> {
+        let energized = self.get_state().await? == (self.active_level == Level::High);
+        Ok(energized != (self.normally == Normally::Closed))
     }
 }
 
@@ -33,19 +179,142 @@ impl SendRecv for DigitalInput {
 #[derive(Clone, Debug)]
 pub struct AnalogInput {
     cmd: [u8; 4],
+    observed: Arc<Mutex<Option<(f64, f64)>>>,
+    adc_range: Arc<Mutex<(u32, f64)>>,
+    /// Slope and offset `read_scaled` applies to the raw counts.
+    calibration: (f64, f64),
     drive_sender: Sender<Message>,
 }
 
 impl AnalogInput {
     pub fn new(id: u8, drive_sender: Sender<Message>) -> Self {
         let cmd = [STX, b'I', int_to_byte(id), CR];
-        Self { cmd, drive_sender }
+        Self {
+            cmd,
+            observed: Arc::new(Mutex::new(None)),
+            adc_range: Arc::new(Mutex::new((DEFAULT_ADC_RESOLUTION, DEFAULT_ADC_SPAN))),
+            calibration: (1., 0.),
+            drive_sender,
+        }
+    }
+
+    pub fn with_calibration(mut self, slope: f64, offset: f64) -> Self {
+        self.set_calibration(slope, offset);
+        self
+    }
+
+    /// Linear conversion from counts to engineering units, `counts * slope + offset`, used by
+    /// `read_scaled`. Unlike the ADC range this belongs to this handle only, not its clones.
+    pub fn set_calibration(&mut self, slope: f64, offset: f64) {
+        self.calibration = (slope, offset);
+    }
+
+    pub fn calibration(&self) -> (f64, f64) {
+        self.calibration
+    }
+
+    /// Full-scale count and voltage span (`I<id>AR`, reply `<counts>,<volts>`). Also becomes the
+    /// range `read_millivolts` converts with, for this input and its clones. Firmware that does
+    /// not report it leaves the range as configured with `set_adc_range`, by default
+    /// `DEFAULT_ADC_RESOLUTION` over `DEFAULT_ADC_SPAN` volts.
+    pub async fn adc_range(&self) -> Result<(u32, f64)> {
+        let range_cmd = codec::encode_get_adc_range(&self.cmd[..3]);
+        let resp = match self.write(range_cmd.as_slice()).await {
+            Err(e) if matches!(e.downcast_ref(), Some(ClearCoreError::Unsupported(_))) => None,
+            resp => Some(resp?),
+        };
+        if let Some(resp) = resp.filter(|resp| resp.get(REPLY_IDX) != Some(&FAILED_REPLY)) {
+            let fields = codec::parse_fields::<f64>(&resp, Layout::Delimited(b','))?;
+            if let [Some(counts), Some(span)] = fields[..] {
+                self.set_adc_range(counts as u32, span);
+            }
+        }
+        Ok(*self.adc_range.lock().expect("adc range poisoned"))
+    }
+
+    /// Range to assume when the firmware does not report one, e.g. for an input configured for
+    /// 0-5 V. Applies to this input and its clones.
+    pub fn set_adc_range(&self, resolution: u32, span: f64) {
+        *self.adc_range.lock().expect("adc range poisoned") = (resolution, span);
+    }
+
+    /// Reading converted to millivolts with the range from `adc_range`.
+    pub async fn read_millivolts(&self) -> Result<f64> {
+        let counts = self.get_state().await?;
+        let (resolution, span) = *self.adc_range.lock().expect("adc range poisoned");
+        Ok(counts as f64 * span * 1000. / resolution as f64)
+    }
+
+    /// Reading in ADC counts.
+    pub async fn read_raw(&self) -> Result<u16> {
+        let counts = self.get_state().await?;
+        u16::try_from(counts).map_err(|_| anyhow!("analog reading {counts} out of range"))
+    }
+
+    /// Reading converted with the calibration from `set_calibration`.
+    pub async fn read_scaled(&self) -> Result<f64> {
+        let counts = self.read_raw().await?;
+        let (slope, offset) = self.calibration;
+        Ok(counts as f64 * slope + offset)
     }
 
     pub async fn get_state(&self) -> Result<isize> {
-        let res = self.write(self.cmd.as_slice()).await;
-        check_reply(&res)?;
-        Ok(ascii_to_int(&res[3..]))
+        let res = self.write(self.cmd.as_slice()).await?;
+        let value = reply_int(&res)?;
+        let mut observed = self.observed.lock().expect("observed range poisoned");
+        let (min, max) = observed.unwrap_or((value as f64, value as f64));
+        *observed = Some((min.min(value as f64), max.max(value as f64)));
+        Ok(value)
+    }
+
+    /// Lowest and highest value read through this input and its clones since creation or the
+    /// last `reset_observed_range`, including reads by `sample_stream`; `None` before the first.
+    pub fn observed_range(&self) -> Option<(f64, f64)> {
+        *self.observed.lock().expect("observed range poisoned")
+    }
+
+    pub fn reset_observed_range(&self) {
+        *self.observed.lock().expect("observed range poisoned") = None;
+    }
+
+    /// Samples the input `rate` times per second into the returned channel, timestamping each
+    /// sample when it was read. Ticks the link cannot keep up with are skipped and logged, so gaps
+    /// show up in the timestamps. Sampling stops when the receiver is dropped or a read fails.
+    /// `rate` must be positive and finite, and its period at least a nanosecond.
+    pub fn sample_stream(&self, rate: f64) -> Result<mpsc::Receiver<(Instant, f64)>> {
+        let period = Duration::try_from_secs_f64(1. / rate)
+            .ok()
+            .filter(|period| rate.is_finite() && !period.is_zero())
+            .ok_or(ClearCoreError::InvalidParameter {
+                parameter: "sample rate",
+                value: rate,
+            })?;
+        let (tx, rx) = mpsc::channel(rate.ceil().max(1.) as usize);
+        let input = self.clone();
+        tokio::spawn(async move {
+            let mut tick_interval = tokio::time::interval(period);
+            tick_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+            let mut last_sample: Option<Instant> = None;
+            loop {
+                tick_interval.tick().await;
+                let sample = match input.get_state().await {
+                    Ok(sample) => sample as f64,
+                    Err(e) => {
+                        error!("Analog sampling stopped: {e}");
+                        break;
+                    }
+                };
+                let now = Instant::now();
+                if last_sample.is_some_and(|last| now.duration_since(last) > period.mul_f64(1.5)) {
+                    warn!("Analog sampling fell behind {rate} Hz");
+                }
+                last_sample = Some(now);
+                if tx.send((now, sample)).await.is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(rx)
     }
 }
 
@@ -57,30 +326,88 @@ impl SendRecv for AnalogInput {
 
 #[derive(Clone, Debug)]
 pub struct DigitalOutput {
-    on_cmd: [u8; 9],
-    off_cmd: [u8; 9],
+    prefix: [u8; 3],
     drive_sender: Sender<Message>,
 }
 
 impl DigitalOutput {
     pub fn new(id: u8, drive_sender: Sender<Message>) -> Self {
-        let on_cmd = [STX, b'O', int_to_byte(id), b'3', b'2', b'7', b'0', b'0', CR];
-        let off_cmd = [STX, b'O', int_to_byte(id), b'0', CR, 0, 0, 0, 0];
         Self {
-            on_cmd,
-            off_cmd,
+            prefix: make_prefix(b'O', id),
             drive_sender,
         }
     }
 
-    fn command_builder(&self, state: bool) -> [u8; 9] {
-        if state { self.on_cmd } else { self.off_cmd }
+    fn command_builder(&self, state: bool) -> Vec<u8> {
+        codec::encode_set_output(&self.prefix, if state { OUTPUT_HIGH } else { 0 })
     }
+
     pub async fn set_state(&self, state: bool) -> Result<()> {
-        let res = self.write(self.command_builder(state).as_slice()).await;
+        let res = self.write(&self.command_builder(state)).await?;
         check_reply(&res)?;
         Ok(())
     }
+
+    /// Reads back the level the controller is driving on the output.
+    pub async fn get_state(&self) -> Result<bool> {
+        let get_output_cmd = codec::encode_command(&self.prefix, b"GO", None);
+        let res = self.write(&get_output_cmd).await?;
+        Ok(reply_int(&res)? != 0)
+    }
+
+    pub async fn set_high(&self) -> Result<()> {
+        self.set_state(true).await
+    }
+
+    pub async fn set_low(&self) -> Result<()> {
+        self.set_state(false).await
+    }
+
+    /// Same as `get_state`.
+    pub async fn read(&self) -> Result<bool> {
+        self.get_state().await
+    }
+
+    /// Drives the output high for `duration`, e.g. to fire a solenoid. If the returned future is
+    /// dropped after the output went high but before it was driven low again, a spawned task
+    /// drives it low.
+    pub async fn pulse(&self, duration: Duration) -> Result<()> {
+        self.set_high().await?;
+        let guard = LowGuard { output: Some(self) };
+        tokio::time::sleep(duration).await;
+        let result = self.set_low().await;
+        guard.disarm();
+        result
+    }
+}
+
+/// Drives the output low from a spawned task if dropped before `disarm`, see `pulse`.
+struct LowGuard<'a> {
+    output: Option<&'a DigitalOutput>,
+}
+
+impl LowGuard<'_> {
+    fn disarm(mut self) {
+        self.output = None;
+    }
+}
+
+impl Drop for LowGuard<'_> {
+    fn drop(&mut self) {
+        let Some(output) = self.output.take() else {
+            return;
+        };
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            error!("Cannot drive output low after cancelled pulse: no runtime");
+            return;
+        };
+        let output = output.clone();
+        runtime.spawn(async move {
+            if let Err(e) = output.set_low().await {
+                error!("Failed to drive output low after cancelled pulse: {e}");
+            }
+        });
+    }
 }
 
 impl SendRecv for DigitalOutput {
@@ -95,41 +422,108 @@ pub enum HBridgeState {
     Neg,
     Off,
 }
+
+/// What the bridge is physically doing, as opposed to whether the last `set_state` was
+/// accepted by the controller (which `set_state` itself reports).
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum HBridgeStatus {
+    Off,
+    Driving,
+    Overcurrent,
+    Overtemperature,
+}
 #[derive(Clone, Debug)]
 pub struct HBridge {
     power: i16,
     prefix: [u8; 3],
+    faults: Option<FaultReporter>,
     drive_sender: Sender<Message>,
 }
 
 impl HBridge {
     pub fn new(id: u8, power: i16, drive_sender: Sender<Message>) -> Self {
-        let prefix = [STX, b'O', int_to_byte(id)];
         Self {
             power,
-            prefix,
+            prefix: make_prefix(b'O', id),
+            faults: None,
             drive_sender,
         }
     }
 
-    fn command_builder(&self, state: HBridgeState) -> Vec<u8> {
-        let state = match state {
-            HBridgeState::Pos => num_to_bytes(self.power),
-            HBridgeState::Neg => num_to_bytes(-self.power),
-            HBridgeState::Off => num_to_bytes(0),
-        };
-        let mut cmd: Vec<u8> = Vec::with_capacity(self.prefix.len() + state.len() + 1);
-        cmd.extend_from_slice(self.prefix.as_slice());
-        cmd.extend_from_slice(state.as_slice());
-        cmd.push(13);
-        cmd
+    pub fn id(&self) -> u8 {
+        self.prefix[2] - b'0'
+    }
+
+    pub(crate) fn with_fault_reporter(mut self, faults: FaultReporter) -> Self {
+        self.faults = Some(faults);
+        self
+    }
+
+    fn command_builder(&self, value: i16) -> Vec<u8> {
+        codec::encode_set_output(&self.prefix, isize::from(value))
     }
 
     pub async fn set_state(&self, state: HBridgeState) -> Result<()> {
-        let resp = self.write(self.command_builder(state).as_slice()).await;
+        match state {
+            HBridgeState::Pos => self.set_output(self.power).await,
+            HBridgeState::Neg => self.set_output(-self.power).await,
+            HBridgeState::Off => self.set_output(0).await,
+        }
+    }
+
+    /// Drives the bridge at `value`, clamped to the `power` it was created with in either
+    /// direction before it is sent.
+    pub async fn set_output(&self, value: i16) -> Result<()> {
+        let max = self.power.saturating_abs();
+        let value = value.clamp(-max, max);
+        let resp = self.write(self.command_builder(value).as_slice()).await?;
+        check_reply(&resp)?;
+        Ok(())
+    }
+
+    /// Shorts the load through the bridge (`O<id>BK`) so a motor on it stops quickly.
+    pub async fn brake(&self) -> Result<()> {
+        let brake_cmd = codec::encode_command(&self.prefix, b"BK", None);
+        let resp = self.write(brake_cmd.as_slice()).await?;
         check_reply(&resp)?;
         Ok(())
     }
+
+    /// Stops driving and lets a motor on the bridge freewheel to a stop.
+    pub async fn coast(&self) -> Result<()> {
+        self.set_output(0).await
+    }
+
+    /// Output currently driven by the bridge, normalized to `-1.0..=1.0` against the `power` it
+    /// was created with.
+    pub async fn get_output(&self) -> Result<f64> {
+        let get_output_cmd = codec::encode_command(&self.prefix, b"GO", None);
+        let resp = self.write(get_output_cmd.as_slice()).await?;
+        let max = self.power.saturating_abs().max(1);
+        Ok(reply_int(&resp)? as f64 / f64::from(max))
+    }
+
+    /// Reply digit: `0` off, `1` driving, `2` overcurrent, `3` overtemperature.
+    pub async fn status(&self) -> Result<HBridgeStatus> {
+        let status_cmd = codec::encode_command(&self.prefix, b"GS", None);
+        let resp = self.write(status_cmd.as_slice()).await?;
+        check_reply(&resp)?;
+        let status = match resp[REPLY_IDX] {
+            b'0' => HBridgeStatus::Off,
+            b'1' => HBridgeStatus::Driving,
+            b'2' => HBridgeStatus::Overcurrent,
+            b'3' => HBridgeStatus::Overtemperature,
+            code => return Err(ClearCoreError::UnknownStatus(code).into()),
+        };
+        if let Some(faults) = &self.faults {
+            faults.update(match status {
+                HBridgeStatus::Overcurrent => Some(FaultKind::Overcurrent),
+                HBridgeStatus::Overtemperature => Some(FaultKind::Overtemperature),
+                HBridgeStatus::Off | HBridgeStatus::Driving => None,
+            });
+        }
+        Ok(status)
+    }
 }
 
 impl SendRecv for HBridge {
@@ -137,3 +531,45 @@ impl SendRecv for HBridge {
         &self.drive_sender
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn sample_stream_rejects_bad_rates() {
+        let (tx, _rx) = mpsc::channel(1);
+        let input = AnalogInput::new(3, tx);
+        for rate in [0., -1., f64::NAN, f64::INFINITY, 1e12] {
+            let error = input.sample_stream(rate).expect_err("rate accepted");
+            assert!(matches!(
+                error.downcast_ref(),
+                Some(ClearCoreError::InvalidParameter {
+                    parameter: "sample rate",
+                    ..
+                })
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn h_bridge_output_is_normalized_to_its_configured_power() {
+        let (tx, mut rx) = mpsc::channel::<Message>(1);
+        let bridge = HBridge::new(4, 32700, tx);
+        tokio::spawn(async move {
+            let msg = rx.recv().await.unwrap();
+            assert_eq!(msg.buffer, b"\x02O4GO\r");
+            msg.response.send(Ok(b"\x02O432700\r".to_vec())).unwrap();
+        });
+        assert_eq!(bridge.get_output().await.unwrap(), 1.0);
+    }
+
+    #[test]
+    fn low_guard_dropped_outside_a_runtime_does_not_panic() {
+        let (tx, _rx) = mpsc::channel(1);
+        let output = DigitalOutput::new(0, tx);
+        drop(LowGuard {
+            output: Some(&output),
+        });
+    }
+}
@@ -1,27 +1,103 @@
+pub mod codec;
+pub mod condition;
 pub mod controller;
+mod diagnostics;
+pub mod error;
+pub mod faults;
+pub mod group;
 mod interface;
 pub mod io;
+#[cfg(feature = "mock")]
+pub mod mock;
 pub mod motor;
 mod send_recv;
+mod tracking;
 
-pub(crate) fn num_to_bytes<T: ToString>(number: T) -> Vec<u8> {
-    number.to_string().chars().map(|c| c as u8).collect()
+/// The TLS stack `Transport::Tls` is built on, for making its `ClientConfig` and `ServerName`.
+#[cfg(feature = "tls")]
+pub use tokio_rustls;
+
+use send_recv::TaskScope;
+use std::future::Future;
+use tokio::task::JoinSet;
+
+/// Decimal argument field. An integer's `to_string` is always ASCII, so the bytes are too.
+pub(crate) fn num_to_bytes(number: isize) -> Vec<u8> {
+    number.to_string().into_bytes()
 }
 
-pub(crate) fn ascii_to_int(bytes: &[u8]) -> isize {
-    let sign = if bytes[0] == 45 { -1 } else { 1 };
-    let int = bytes
+/// Parses the digits in `bytes`, ignoring anything else; a `-` before the first digit makes the
+/// result negative. Pass the reply payload, not the whole frame. `None` if there are no digits or
+/// the number does not fit an `isize`.
+pub(crate) fn ascii_to_int(bytes: &[u8]) -> Option<isize> {
+    if !bytes.iter().any(u8::is_ascii_digit) {
+        return None;
+    }
+    let negative = bytes
+        .iter()
+        .take_while(|x| !x.is_ascii_digit())
+        .any(|&x| x == b'-');
+    let sign = if negative { -1 } else { 1 };
+    // Accumulated with the sign applied so `isize::MIN` does not overflow.
+    bytes
         .iter()
         .filter(|&&x| (48..=57).contains(&x))
-        .fold(0, |mut acc, x| {
-            let num = x - 48;
-            acc *= 10;
-            acc += num as isize;
-            acc
-        });
-    int * sign
+        .try_fold(0isize, |acc, x| {
+            let num = (x - 48) as isize;
+            acc.checked_mul(10)?.checked_add(num * sign)
+        })
 }
 
 pub(crate) fn int_to_byte(number: u8) -> u8 {
     number + 48
 }
+
+/// Runs the futures concurrently and returns their outputs in input order. Outstanding futures
+/// are aborted if the returned future is dropped. The caller's command context and critical
+/// section carry over.
+pub(crate) async fn join_all<F>(futures: impl IntoIterator<Item = F>) -> Vec<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let mut set = JoinSet::new();
+    let mut len = 0;
+    let scope = TaskScope::current();
+    for (idx, future) in futures.into_iter().enumerate() {
+        set.spawn(scope.clone().run(async move { (idx, future.await) }));
+        len += 1;
+    }
+    let mut outputs: Vec<Option<F::Output>> = (0..len).map(|_| None).collect();
+    while let Some(res) = set.join_next().await {
+        match res {
+            Ok((idx, output)) => outputs[idx] = Some(output),
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        }
+    }
+    outputs.into_iter().flatten().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ascii_to_int_signs() {
+        assert_eq!(ascii_to_int(b"-1"), Some(-1));
+        assert_eq!(ascii_to_int(b"-0"), Some(0));
+        assert_eq!(ascii_to_int(b"-123456"), Some(-123456));
+        assert_eq!(ascii_to_int(b"123456"), Some(123456));
+        assert_eq!(ascii_to_int(b"-"), None);
+        assert_eq!(ascii_to_int(b""), None);
+    }
+
+    #[test]
+    fn num_to_bytes_round_trips() {
+        for number in [isize::MIN, -1, 0, 1, isize::MAX] {
+            let bytes = num_to_bytes(number);
+            assert!(bytes.is_ascii(), "{bytes:?}");
+            assert_eq!(ascii_to_int(&bytes), Some(number));
+        }
+        assert_eq!(ascii_to_int(b"99999999999999999999"), None);
+    }
+}
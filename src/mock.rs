@@ -0,0 +1,826 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use anyhow::Result;
+use log::{error, info};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::Instant;
+
+use crate::controller::{CR, STX, Transport};
+use crate::io::Level;
+use crate::num_to_bytes;
+
+const MOCK_MOTORS: usize = 4;
+/// Bytes an in-memory link buffers in each direction; far more than one frame.
+const LINK_BUFFER: usize = 4096;
+/// Firmware version the mock reports.
+pub const MOCK_FIRMWARE: &str = "mock-1.0";
+const REJECT: &[u8] = b"?";
+const REJECT_UNKNOWN: &[u8] = b"?1";
+const REJECT_NOT_ENABLED: &[u8] = b"?3";
+const REJECT_FAULTED: &[u8] = b"?4";
+
+#[derive(Debug, Default)]
+struct MockMotor {
+    enabled: bool,
+    faulted: bool,
+    alerts: u32,
+    position: isize,
+    hlfb_mode: isize,
+    /// Counts per second, and per second squared.
+    velocity: isize,
+    acceleration: isize,
+    /// Tenths of a percent of peak torque.
+    torque: isize,
+    jogging: bool,
+    moving_until: Option<Instant>,
+}
+
+impl MockMotor {
+    fn moving(&self) -> bool {
+        self.jogging
+            || self
+                .moving_until
+                .is_some_and(|until| Instant::now() < until)
+    }
+
+    fn halt(&mut self) {
+        self.jogging = false;
+        self.moving_until = None;
+    }
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    motors: [MockMotor; MOCK_MOTORS],
+    move_duration: Duration,
+    inputs: HashMap<u8, isize>,
+    input_filters: HashMap<u8, isize>,
+    stuck_inputs: HashMap<u8, Level>,
+    outputs: HashMap<u8, isize>,
+    failed_outputs: HashSet<u8>,
+}
+
+/// In-process stand-in for a ClearCore speaking the client's protocol on a local TCP port, so
+/// code built on `ControllerHandle` can be exercised without hardware. Moves finish after
+/// `set_move_duration`; the `inject_*`/`stick_*`/`fail_*` methods drive error paths. The server
+/// stops when the mock is dropped.
+///
+/// The mock and the client keep time with `tokio::time` only, so tests can run on a paused
+/// clock (`#[tokio::test(start_paused = true)]`, enabled by this feature) and have move
+/// durations, settle delays and reply timeouts elapse without waiting. Connect through
+/// `transport()` for that: a paused runtime waiting on a socket advances the clock to its next
+/// timer, so over TCP every round trip can take as long as the pending reply timeout, or time
+/// it out. The in-memory link wakes the other side directly, so time only passes while both
+/// sides wait on timers.
+pub struct MockController {
+    addr: SocketAddr,
+    state: Arc<Mutex<MockState>>,
+    server: JoinHandle<()>,
+    /// Connections made through `transport()`, aborted with the mock.
+    links: Arc<Mutex<JoinSet<()>>>,
+}
+
+impl MockController {
+    /// Starts listening on an ephemeral localhost port; connect to `addr()`.
+    pub async fn start() -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(MockState::default()));
+        let server_state = state.clone();
+        let server = tokio::spawn(async move {
+            let mut connections = JoinSet::new();
+            loop {
+                match listener.accept().await {
+                    Ok((stream, peer)) => {
+                        info!("Mock controller accepted {peer}");
+                        connections.spawn(serve(stream, server_state.clone()));
+                    }
+                    Err(e) => {
+                        error!("Mock controller failed to accept: {e}");
+                        break;
+                    }
+                }
+            }
+        });
+        Ok(Self {
+            addr,
+            state,
+            server,
+            links: Arc::new(Mutex::new(JoinSet::new())),
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Connects the client to this mock in memory instead of over TCP, for tests on a paused
+    /// clock; set it with `ControllerConfig::with_transport`. The address the client is given is
+    /// then unused. Connecting fails with `NotConnected` once the mock is dropped.
+    pub fn transport(&self) -> Transport {
+        let state = self.state.clone();
+        let links = Arc::downgrade(&self.links);
+        Transport::connect(move || {
+            let (client, server) = tokio::io::duplex(LINK_BUFFER);
+            let served = links.upgrade().map(|links| {
+                let mut links = links.lock().expect("mock links poisoned");
+                links.spawn(serve(server, state.clone()));
+            });
+            async move {
+                served
+                    .map(|()| client)
+                    .ok_or_else(|| std::io::ErrorKind::NotConnected.into())
+            }
+        })
+    }
+
+    /// Closes every connection made through `transport()`, as when the controller reboots; the
+    /// client then reconnects through the same transport.
+    pub fn drop_connections(&self) {
+        self.links.lock().expect("mock links poisoned").abort_all();
+    }
+
+    fn state(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().expect("mock state poisoned")
+    }
+
+    /// How long each commanded move reports `Moving` before it completes. Zero by default.
+    pub fn set_move_duration(&self, duration: Duration) {
+        self.state().move_duration = duration;
+    }
+
+    /// Motor position in counts.
+    pub fn motor_position(&self, motor_id: usize) -> isize {
+        self.state().motors[motor_id].position
+    }
+
+    /// Faults the motor, aborting any move in progress, and latches `alerts` into its alert
+    /// register until the client clears them.
+    pub fn inject_fault(&self, motor_id: usize, alerts: u32) {
+        let mut state = self.state();
+        let motor = &mut state.motors[motor_id];
+        motor.faulted = true;
+        motor.alerts |= alerts;
+        motor.halt();
+    }
+
+    /// Torque motor `motor_id` reports, in percent of peak.
+    pub fn set_motor_torque(&self, motor_id: usize, percent: f64) {
+        self.state().motors[motor_id].torque = (percent * 10.).round() as isize;
+    }
+
+    /// Raw value digital or analog input `id` reads as.
+    pub fn set_input(&self, id: u8, value: isize) {
+        self.state().inputs.insert(id, value);
+    }
+
+    /// Makes input `id` read `level` regardless of `set_input`, like a welded contact.
+    pub fn stick_input(&self, id: u8, level: Level) {
+        self.state().stuck_inputs.insert(id, level);
+    }
+
+    pub fn release_input(&self, id: u8) {
+        self.state().stuck_inputs.remove(&id);
+    }
+
+    /// Value last driven on output or h-bridge `id`.
+    pub fn output_value(&self, id: u8) -> isize {
+        self.state().outputs.get(&id).copied().unwrap_or_default()
+    }
+
+    /// Makes every command to output or h-bridge `id` be rejected.
+    pub fn fail_output(&self, id: u8) {
+        self.state().failed_outputs.insert(id);
+    }
+
+    pub fn restore_output(&self, id: u8) {
+        self.state().failed_outputs.remove(&id);
+    }
+}
+
+impl Drop for MockController {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+async fn serve(mut stream: impl AsyncRead + AsyncWrite + Unpin, state: Arc<Mutex<MockState>>) {
+    let mut pending = Vec::new();
+    let mut buffer = [0; 256];
+    loop {
+        let n = match stream.read(&mut buffer).await {
+            Ok(0) => return,
+            Ok(n) => n,
+            Err(e) => {
+                error!("Mock controller read failed: {e}");
+                return;
+            }
+        };
+        pending.extend_from_slice(&buffer[..n]);
+        while let Some(end) = pending.iter().position(|&b| b == CR) {
+            let frame: Vec<u8> = pending.drain(..=end).collect();
+            let reply = respond(&frame, &mut state.lock().expect("mock state poisoned"));
+            if let Err(e) = stream.write_all(&reply).await {
+                error!("Mock controller write failed: {e}");
+                return;
+            }
+        }
+    }
+}
+
+/// Reply to one `STX, device, id, body..., CR` frame.
+fn respond(frame: &[u8], state: &mut MockState) -> Vec<u8> {
+    let (device, id, body) = match frame {
+        [STX, device, id, body @ .., CR] => (*device, *id, body),
+        _ => return vec![STX, b'?', b'0', b'?', CR],
+    };
+    let payload = match device {
+        b'M' => motor_reply(state, id.wrapping_sub(b'0'), body),
+        b'I' => input_reply(state, id.wrapping_sub(b'0'), body),
+        b'O' => output_reply(state, id.wrapping_sub(b'0'), body),
+        b'C' => match body {
+            b"ID" => b"MOCK".to_vec(),
+            b"FV" => MOCK_FIRMWARE.as_bytes().to_vec(),
+            _ => REJECT_UNKNOWN.to_vec(),
+        },
+        _ => REJECT.to_vec(),
+    };
+    let mut reply = vec![STX, device, id];
+    reply.extend_from_slice(&payload);
+    reply.push(CR);
+    reply
+}
+
+fn parse_arg(arg: &[u8]) -> Option<isize> {
+    std::str::from_utf8(arg).ok()?.parse().ok()
+}
+
+fn motor_reply(state: &mut MockState, id: u8, body: &[u8]) -> Vec<u8> {
+    let move_duration = state.move_duration;
+    let Some(motor) = state.motors.get_mut(id as usize) else {
+        return REJECT.to_vec();
+    };
+    let (mnemonic, arg) = body.split_at(body.len().min(2));
+    let arg = parse_arg(arg);
+    match (mnemonic, arg) {
+        (b"EN", None) => motor.enabled = true,
+        (b"DE", None) => {
+            motor.enabled = false;
+            motor.halt();
+        }
+        (b"AM" | b"RM" | b"JG", Some(_)) if motor.faulted => return REJECT_FAULTED.to_vec(),
+        (b"AM" | b"RM" | b"JG", Some(_)) if !motor.enabled => {
+            return REJECT_NOT_ENABLED.to_vec();
+        }
+        (b"AM", Some(counts)) => {
+            motor.position = counts;
+            motor.moving_until = Some(Instant::now() + move_duration);
+        }
+        (b"RM", Some(counts)) => {
+            motor.position += counts;
+            motor.moving_until = Some(Instant::now() + move_duration);
+        }
+        (b"JG", Some(_)) => motor.jogging = true,
+        (b"HM", None) if motor.faulted => return REJECT_FAULTED.to_vec(),
+        (b"HM", None) if !motor.enabled => return REJECT_NOT_ENABLED.to_vec(),
+        (b"HM", None) => {
+            motor.position = 0;
+            motor.moving_until = Some(Instant::now() + move_duration);
+        }
+        (b"ST" | b"AS", None) => motor.halt(),
+        (b"SP", Some(counts)) => motor.position = counts,
+        (b"SM", Some(_)) if motor.enabled => return REJECT.to_vec(),
+        (b"SM", Some(mode)) => motor.hlfb_mode = mode,
+        (b"SV", Some(velocity)) => motor.velocity = velocity,
+        (b"SA", Some(acceleration)) => motor.acceleration = acceleration,
+        (b"SD" | b"TL", Some(_)) => {}
+        (b"CA", None) => {
+            motor.alerts = 0;
+            motor.faulted = false;
+        }
+        (b"GS", None) => {
+            let status = if motor.faulted {
+                b'2'
+            } else if !motor.enabled {
+                b'0'
+            } else if motor.moving() {
+                b'4'
+            } else {
+                b'3'
+            };
+            return vec![status];
+        }
+        (b"GP", None) => return num_to_bytes(motor.position),
+        (b"VD", None) => return b"0,0".to_vec(),
+        (b"AL", None) => return num_to_bytes(motor.alerts as isize),
+        (b"GH", None) => return num_to_bytes(isize::from(motor.enabled && !motor.moving())),
+        (b"GM", None) => return num_to_bytes(motor.hlfb_mode),
+        (b"GT", None) => return num_to_bytes(motor.torque),
+        (b"GV", None) => return num_to_bytes(motor.velocity),
+        (b"GA", None) => return num_to_bytes(motor.acceleration),
+        _ => return REJECT_UNKNOWN.to_vec(),
+    }
+    Vec::new()
+}
+
+fn input_reply(state: &mut MockState, id: u8, body: &[u8]) -> Vec<u8> {
+    match body {
+        [] => {
+            let value = match state.stuck_inputs.get(&id) {
+                Some(Level::High) => 1,
+                Some(Level::Low) => 0,
+                None => state.inputs.get(&id).copied().unwrap_or_default(),
+            };
+            num_to_bytes(value)
+        }
+        b"GF" => num_to_bytes(state.input_filters.get(&id).copied().unwrap_or_default()),
+        [b'S', b'F', samples @ ..] => match parse_arg(samples) {
+            Some(samples) => {
+                state.input_filters.insert(id, samples);
+                Vec::new()
+            }
+            None => REJECT.to_vec(),
+        },
+        _ => REJECT_UNKNOWN.to_vec(),
+    }
+}
+
+fn output_reply(state: &mut MockState, id: u8, body: &[u8]) -> Vec<u8> {
+    if state.failed_outputs.contains(&id) {
+        return REJECT.to_vec();
+    }
+    let value = state.outputs.get(&id).copied().unwrap_or_default();
+    match body {
+        b"GO" => num_to_bytes(value),
+        b"GS" => num_to_bytes(isize::from(value != 0)),
+        b"BK" => {
+            state.outputs.insert(id, 0);
+            Vec::new()
+        }
+        _ => match parse_arg(body) {
+            Some(value) => {
+                state.outputs.insert(id, value);
+                Vec::new()
+            }
+            None => REJECT.to_vec(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::{ControllerConfig, ControllerHandle};
+    use crate::error::ClearCoreError;
+    use crate::group::GangedAxis;
+    use crate::motor::{
+        AlertKind, ClearCoreMotor, Confidence, HlfbMode, IntoCounts, LimitMode, MotorBuilder,
+        MoveOutcome, Ramp,
+    };
+
+    fn in_memory(mock: &MockController) -> ControllerConfig {
+        ControllerConfig::default().with_transport(mock.transport())
+    }
+
+    /// A mock with motor 0 at 800 counts per unit, connected in memory.
+    async fn connected_motor() -> (MockController, ClearCoreMotor) {
+        let mock = MockController::start().await.unwrap();
+        let controller =
+            ControllerHandle::connect(mock.addr(), [MotorBuilder::new(0, 800)], in_memory(&mock))
+                .await
+                .unwrap();
+        let motor = controller.get_motor(0).unwrap();
+        (mock, motor)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn paused_clock_drives_blocking_move() {
+        let (mock, motor) = connected_motor().await;
+        mock.set_move_duration(Duration::from_secs(30));
+        motor.enable().await.unwrap();
+        let started = Instant::now();
+        motor.get_position().await.unwrap();
+        assert_eq!(
+            started.elapsed(),
+            Duration::ZERO,
+            "round trip advanced the clock"
+        );
+        let outcome = motor
+            .absolute_move_blocking(10., Duration::from_millis(100))
+            .await
+            .unwrap();
+        assert_eq!(outcome, MoveOutcome::Completed);
+        assert_eq!(mock.motor_position(0), 8000);
+        assert!(started.elapsed() >= Duration::from_secs(30));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn coordinated_move_falls_back_to_controller_velocity() {
+        let mock = MockController::start().await.unwrap();
+        let motors = [MotorBuilder::new(0, 800), MotorBuilder::new(1, 800)];
+        let first = ControllerHandle::connect(mock.addr(), motors.clone(), in_memory(&mock))
+            .await
+            .unwrap();
+        first.get_motor(1).unwrap().set_velocity(5.).await.unwrap();
+        let controller = ControllerHandle::connect(mock.addr(), motors, in_memory(&mock))
+            .await
+            .unwrap();
+        for id in [0, 1] {
+            controller.get_motor(id).unwrap().enable().await.unwrap();
+        }
+        controller
+            .get_motor(0)
+            .unwrap()
+            .set_velocity(10.)
+            .await
+            .unwrap();
+        controller.coordinated_move(&[20., 5.]).await.unwrap();
+        assert_eq!(mock.motor_position(0), 16000);
+        assert_eq!(mock.motor_position(1), 4000);
+        let state = mock.state();
+        assert_eq!(state.motors[0].velocity, 8000);
+        assert_eq!(state.motors[1].velocity, 4000);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn coordinated_move_stops_started_axes_when_one_fails() {
+        let mock = MockController::start().await.unwrap();
+        mock.set_move_duration(Duration::from_secs(30));
+        let controller = ControllerHandle::connect(
+            mock.addr(),
+            [MotorBuilder::new(0, 800), MotorBuilder::new(1, 800)],
+            in_memory(&mock),
+        )
+        .await
+        .unwrap();
+        let (moving, disabled) = (
+            controller.get_motor(0).unwrap(),
+            controller.get_motor(1).unwrap(),
+        );
+        moving.enable().await.unwrap();
+        moving.set_velocity(10.).await.unwrap();
+        disabled.set_velocity(10.).await.unwrap();
+        assert!(controller.coordinated_move(&[20., 5.]).await.is_err());
+        let state = mock.state();
+        assert!(!state.motors[0].moving());
+        assert_eq!(state.motors[0].velocity, 8000);
+        assert_eq!(state.motors[1].velocity, 8000);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn clamped_relative_move_during_jog_stays_within_limits() {
+        let mock = MockController::start().await.unwrap();
+        let motor = MotorBuilder::new(0, 800)
+            .with_soft_limits(0., 100.)
+            .with_limit_mode(LimitMode::Clamp);
+        let controller = ControllerHandle::connect(mock.addr(), [motor], in_memory(&mock))
+            .await
+            .unwrap();
+        let motor = controller.get_motor(0).unwrap();
+        motor.enable().await.unwrap();
+        motor.absolute_move(10.).await.unwrap();
+        motor.jog(5.).await.unwrap();
+        motor.relative_move(200.).await.unwrap();
+        assert_eq!(mock.motor_position(0), 80000);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn negative_or_nan_motion_limits_are_rejected_unsent() {
+        let (mock, motor) = connected_motor().await;
+        motor.set_velocity(10.).await.unwrap();
+        motor.set_acceleration(20.).await.unwrap();
+        let invalid = |parameter, value| ClearCoreError::InvalidParameter { parameter, value };
+        let error = motor.set_velocity(-1.).await.unwrap_err();
+        assert_eq!(error.downcast_ref(), Some(&invalid("velocity", -1.)));
+        let error = motor.set_acceleration(f64::NAN).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(ClearCoreError::InvalidParameter { parameter: "acceleration", value })
+                if value.is_nan()
+        ));
+        let error = motor.set_deceleration(-0.5).await.unwrap_err();
+        assert_eq!(error.downcast_ref(), Some(&invalid("deceleration", -0.5)));
+        let state = mock.state();
+        assert_eq!(state.motors[0].velocity, 8000);
+        assert_eq!(state.motors[0].acceleration, 16000);
+        drop(state);
+        assert_eq!(motor.commanded_velocity(), Some(10.));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ganged_axis_stops_both_sides_when_one_faults() {
+        let mock = Arc::new(MockController::start().await.unwrap());
+        mock.set_move_duration(Duration::from_secs(30));
+        let controller = ControllerHandle::connect(
+            mock.addr(),
+            [MotorBuilder::new(0, 800), MotorBuilder::new(1, 800)],
+            in_memory(&mock),
+        )
+        .await
+        .unwrap();
+        let (primary, secondary) = (
+            controller.get_motor(0).unwrap(),
+            controller.get_motor(1).unwrap(),
+        );
+        primary.enable().await.unwrap();
+        secondary.enable().await.unwrap();
+        let injector = mock.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            injector.inject_fault(1, 0);
+        });
+        let axis = GangedAxis::new(primary, secondary, 1.);
+        let error = axis
+            .absolute_move(10., Duration::from_millis(100))
+            .await
+            .expect_err("faulted gantry move succeeded");
+        assert_eq!(error.downcast_ref(), Some(&ClearCoreError::Faulted));
+        assert!(!mock.state().motors[0].moving());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fault_during_blocking_move_is_an_error() {
+        let (mock, motor) = connected_motor().await;
+        let mock = Arc::new(mock);
+        mock.set_move_duration(Duration::from_secs(30));
+        motor.enable().await.unwrap();
+        let injector = mock.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            injector.inject_fault(0, 1 << 6);
+        });
+        let started = Instant::now();
+        let error = motor
+            .absolute_move_blocking(10., Duration::from_millis(100))
+            .await
+            .expect_err("faulted move succeeded");
+        assert_eq!(
+            error.downcast_ref::<ClearCoreError>(),
+            Some(&ClearCoreError::Faulted)
+        );
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fault_during_move_is_an_outcome_with_its_alerts() {
+        let (mock, motor) = connected_motor().await;
+        let mock = Arc::new(mock);
+        mock.set_move_duration(Duration::from_secs(30));
+        motor.enable().await.unwrap();
+        motor.absolute_move(10.).await.unwrap();
+        mock.inject_fault(0, 1 << 5);
+        let outcome = motor
+            .wait_for_move_outcome(Duration::from_millis(100), None)
+            .await
+            .unwrap();
+        assert_eq!(outcome, MoveOutcome::Faulted(vec![AlertKind::MotorFaulted]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn peak_stats_track_torque_during_a_move() {
+        let (mock, motor) = connected_motor().await;
+        mock.set_move_duration(Duration::from_secs(1));
+        motor.enable().await.unwrap();
+        motor.set_peak_tracking(true);
+        mock.set_motor_torque(0, -42.5);
+        motor.absolute_move(10.).await.unwrap();
+        motor
+            .wait_for_move(Duration::from_millis(100))
+            .await
+            .unwrap();
+        assert_eq!(motor.peak_stats().torque, Some(42.5));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn into_counts_gets_a_positive_scale_on_an_inverted_motor() {
+        struct Counts(isize);
+        impl IntoCounts for Counts {
+            fn into_counts(self, scale: f64) -> isize {
+                assert!(scale > 0., "negative scale {scale}");
+                self.0
+            }
+        }
+        let mock = MockController::start().await.unwrap();
+        let controller = ControllerHandle::connect(
+            mock.addr(),
+            [MotorBuilder::new(0, 800).with_inverted()],
+            in_memory(&mock),
+        )
+        .await
+        .unwrap();
+        let motor = controller.get_motor(0).unwrap();
+        motor.enable().await.unwrap();
+        motor.absolute_move(Counts(1600)).await.unwrap();
+        motor
+            .wait_for_move(Duration::from_millis(100))
+            .await
+            .unwrap();
+        assert_eq!(mock.motor_position(0), -1600);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waiting_on_a_missing_input_is_an_error() {
+        let mock = MockController::start().await.unwrap();
+        let controller =
+            ControllerHandle::connect(mock.addr(), [MotorBuilder::new(0, 800)], in_memory(&mock))
+                .await
+                .unwrap();
+        let error = controller
+            .wait_for(crate::condition::input_high(99))
+            .await
+            .expect_err("waited on a missing input");
+        assert!(format!("{error:#}").contains("no digital input 99"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ramp_reads_acceleration_not_set_through_the_client() {
+        let (mock, motor) = connected_motor().await;
+        mock.state().motors[0].acceleration = 1600;
+        motor.get_ramp().await.expect_err("deceleration is unknown");
+        motor.set_deceleration(2.).await.unwrap();
+        assert_eq!(motor.get_ramp().await.unwrap(), Ramp::Symmetric(2.));
+        motor.set_acceleration(4.).await.unwrap();
+        assert_eq!(
+            motor.get_ramp().await.unwrap(),
+            Ramp::Asymmetric {
+                acceleration: 4.,
+                deceleration: 2.
+            }
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unsettled_motor_times_out_waiting_in_position() {
+        let (_mock, motor) = connected_motor().await;
+        let timeout = Duration::from_secs(1);
+        let error = motor
+            .wait_for_in_position(timeout)
+            .await
+            .expect_err("disabled motor settled");
+        assert_eq!(
+            error.downcast_ref::<ClearCoreError>(),
+            Some(&ClearCoreError::Timeout(timeout))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconnect_reconciles_position_estimates() {
+        let (mock, motor) = connected_motor().await;
+        mock.set_move_duration(Duration::from_secs(30));
+        motor.enable().await.unwrap();
+        motor.set_velocity(1.).await.unwrap();
+        motor.absolute_move(10.).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        mock.state().motors[0] = MockMotor::default();
+        mock.drop_connections();
+        motor.get_status().await.expect_err("link is down");
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert!(motor.position_lost());
+        let (position, confidence) = motor.estimated_position();
+        assert_eq!(position, 0.);
+        assert_ne!(confidence, Confidence::None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unknown_extension_is_unsupported() {
+        let mock = MockController::start().await.unwrap();
+        let controller =
+            ControllerHandle::connect(mock.addr(), [MotorBuilder::new(0, 800)], in_memory(&mock))
+                .await
+                .unwrap();
+        let error = controller.power_status().await.unwrap_err();
+        assert_eq!(
+            error.downcast_ref(),
+            Some(&ClearCoreError::Unsupported("power status query (C0PW)"))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn digital_output_drives_full_scale_and_zero() {
+        let mock = MockController::start().await.unwrap();
+        let controller = ControllerHandle::connect(mock.addr(), [], in_memory(&mock))
+            .await
+            .unwrap();
+        let output = controller.get_output(3).unwrap();
+        output.set_high().await.unwrap();
+        assert_eq!(mock.output_value(3), 32700);
+        output.set_low().await.unwrap();
+        assert_eq!(mock.output_value(3), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn adc_range_falls_back_when_unsupported() {
+        let mock = MockController::start().await.unwrap();
+        let controller = ControllerHandle::connect(mock.addr(), [], in_memory(&mock))
+            .await
+            .unwrap();
+        let input = controller.get_analog_input(0).unwrap();
+        input.set_adc_range(1023, 5.);
+        assert_eq!(input.adc_range().await.unwrap(), (1023, 5.));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rejected_blocking_move_leaves_the_jog_running() {
+        let mock = MockController::start().await.unwrap();
+        let motor = MotorBuilder::new(0, 800).with_soft_limits(-10., 10.);
+        let controller = ControllerHandle::connect(mock.addr(), [motor], in_memory(&mock))
+            .await
+            .unwrap();
+        let motor = controller.get_motor(0).unwrap();
+        motor.enable().await.unwrap();
+        motor.jog(1.).await.unwrap();
+        let error = motor
+            .absolute_move_blocking(20., Duration::from_millis(100))
+            .await
+            .expect_err("move beyond the limit accepted");
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(ClearCoreError::OutOfRange { .. })
+        ));
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(mock.state().motors[0].jogging);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn debounce_rounds_to_the_nearest_sample() {
+        let mock = MockController::start().await.unwrap();
+        let controller = ControllerHandle::connect(mock.addr(), [], in_memory(&mock))
+            .await
+            .unwrap();
+        let input = controller.get_digital_input(1).unwrap();
+        for (micros, samples) in [(250, 1), (300, 2), (399, 2), (500, 3)] {
+            input
+                .set_debounce(Duration::from_micros(micros))
+                .await
+                .unwrap();
+            assert_eq!(mock.state().input_filters[&1], samples, "{micros} µs");
+        }
+    }
+
+    #[test]
+    fn truncated_frames_are_rejected() {
+        let mut state = MockState::default();
+        for frame in [&[STX, b'M', CR][..], &[STX, CR], &[CR], &[STX, b'M', b'0']] {
+            let reply = respond(frame, &mut state);
+            assert_eq!(reply.get(3), Some(&b'?'), "{frame:?}");
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn motion_limits_read_back_in_user_units() {
+        let (_mock, motor) = connected_motor().await;
+        motor.set_velocity(2.5).await.unwrap();
+        motor.set_acceleration(10.).await.unwrap();
+        assert_eq!(motor.get_velocity().await.unwrap(), 2.5);
+        assert_eq!(motor.get_acceleration().await.unwrap(), 10.);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn torque_reads_in_percent_of_peak() {
+        let (mock, motor) = connected_motor().await;
+        mock.set_motor_torque(0, 37.5);
+        assert_eq!(motor.get_torque().await.unwrap(), 37.5);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn alert_register_latches_until_cleared() {
+        let (mock, motor) = connected_motor().await;
+        mock.inject_fault(0, 1 << 5);
+        assert_eq!(motor.get_alert_register().await.unwrap(), 1 << 5);
+        motor.clear_alerts().await.unwrap();
+        assert_eq!(motor.get_alert_register().await.unwrap(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn home_returns_to_zero() {
+        let (mock, motor) = connected_motor().await;
+        mock.set_move_duration(Duration::from_secs(1));
+        motor.enable().await.unwrap();
+        motor.absolute_move(10.).await.unwrap();
+        motor
+            .wait_for_move(Duration::from_millis(100))
+            .await
+            .unwrap();
+        motor.home(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(mock.motor_position(0), 0);
+        assert!(!motor.position_lost());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn hlfb_mode_changes_only_while_disabled() {
+        let (_mock, motor) = connected_motor().await;
+        motor.set_hlfb_mode(HlfbMode::HasBipolarPwm).await.unwrap();
+        assert_eq!(motor.hlfb_mode().await.unwrap(), HlfbMode::HasBipolarPwm);
+        motor.enable().await.unwrap();
+        assert!(motor.get_hlfb().await.unwrap());
+        motor
+            .set_hlfb_mode(HlfbMode::Static)
+            .await
+            .expect_err("mode changed while enabled");
+    }
+}
@@ -1,23 +1,342 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::time::Duration;
 
-use anyhow::{Result, anyhow};
-use serde::Serialize;
+use anyhow::{Context, Result, anyhow};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc::Sender;
-use tokio::time::MissedTickBehavior;
+use tokio::sync::watch;
+use tokio::time::{Instant, MissedTickBehavior};
 
-use crate::controller::{Message, check_reply, make_prefix};
+use crate::codec;
+use crate::codec::Layout;
+use crate::controller::{Message, REPLY_IDX, check_reply, make_prefix, reply_int};
+use crate::error::ClearCoreError;
+use crate::faults::{FaultKind, FaultReporter};
+use crate::io::{DigitalInput, DigitalOutput, Edge};
 use crate::send_recv::SendRecv;
-use crate::{ascii_to_int, num_to_bytes};
+use crate::tracking::MotionState;
 
+const IN_POSITION_POLL: Duration = Duration::from_millis(10);
+const WAYPOINT_POLL: Duration = Duration::from_millis(50);
+const PHASE_SAMPLE_GAP: Duration = Duration::from_millis(20);
+const CRUISE_TOLERANCE: f64 = 0.02;
 
+// ClearCore `MotorDriver::AlertReg` bits
+const ALERT_POSITIVE_LIMIT: u32 = 1 << 1;
+const ALERT_NEGATIVE_LIMIT: u32 = 1 << 2;
+
+/// Converts a position in user units into motor counts given the motor's counts-per-unit scale.
+pub trait IntoCounts {
+    /// `scale` is always positive: for an inverted motor the sign of the result is flipped
+    /// afterwards, so implementations need not know the motor's direction.
+    fn into_counts(self, scale: f64) -> isize;
+}
+
+impl IntoCounts for f64 {
+    fn into_counts(self, scale: f64) -> isize {
+        (self * scale).trunc() as isize
+    }
+}
+
+pub type HomingRoutine =
+    Arc<dyn Fn(ClearCoreMotor) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> + Send + Sync>;
+
+/// What to do with moves once the motor's position register can no longer be trusted (e.g. the
+/// controller rebooted or the link was re-established).
+#[derive(Clone, Default)]
+pub enum HomingPolicy {
+    /// Moves keep being sent; re-homing is left to the caller.
+    #[default]
+    Manual,
+    /// The next move first runs the routine, and every move waits until it has succeeded.
+    RehomeOnPositionLoss(HomingRoutine),
+}
+
+impl HomingPolicy {
+    pub fn rehome<F, Fut>(routine: F) -> Self
+    where
+        F: Fn(ClearCoreMotor) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        HomingPolicy::RehomeOnPositionLoss(Arc::new(move |motor| Box::pin(routine(motor))))
+    }
+}
 
 #[derive(Clone)]
 pub struct MotorBuilder {
     pub id: usize,
     pub scale: usize,
+    pub brake: Option<u8>,
+    pub homing: HomingPolicy,
+    pub settle_delay: Duration,
+    pub min_cycle_interval: Duration,
+    pub limits: Option<(f64, f64)>,
+    pub limit_mode: LimitMode,
+    pub invert: bool,
+}
+
+impl MotorBuilder {
+    pub fn new(id: usize, scale: usize) -> Self {
+        Self {
+            id,
+            scale,
+            brake: None,
+            homing: HomingPolicy::Manual,
+            settle_delay: Duration::ZERO,
+            min_cycle_interval: Duration::ZERO,
+            limits: None,
+            limit_mode: LimitMode::Reject,
+            invert: false,
+        }
+    }
+
+    /// Holding brake driven by digital output `output` (energized = released).
+    pub fn with_brake(mut self, output: u8) -> Self {
+        self.brake = Some(output);
+        self
+    }
+
+    pub fn with_homing(mut self, homing: HomingPolicy) -> Self {
+        self.homing = homing;
+        self
+    }
+
+    /// See `ClearCoreMotor::with_settle_delay`.
+    pub fn with_settle_delay(mut self, settle_delay: Duration) -> Self {
+        self.settle_delay = settle_delay;
+        self
+    }
+
+    /// See `ClearCoreMotor::with_min_cycle_interval`.
+    pub fn with_min_cycle_interval(mut self, interval: Duration) -> Self {
+        self.min_cycle_interval = interval;
+        self
+    }
+
+    /// See `ClearCoreMotor::with_soft_limits`.
+    pub fn with_soft_limits(mut self, min: f64, max: f64) -> Self {
+        self.limits = Some((min, max));
+        self
+    }
+
+    /// See `ClearCoreMotor::with_limit_mode`.
+    pub fn with_limit_mode(mut self, mode: LimitMode) -> Self {
+        self.limit_mode = mode;
+        self
+    }
+
+    /// See `ClearCoreMotor::with_inverted`.
+    pub fn with_inverted(mut self) -> Self {
+        self.invert = true;
+        self
+    }
+}
+
+/// What a move beyond the soft limits does.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LimitMode {
+    /// Fails with `ClearCoreError::OutOfRange` without sending anything.
+    #[default]
+    Reject,
+    /// Moves to the limit instead, to within one count, and logs a warning.
+    Clamp,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MotionProfile {
+    pub velocity: f64,
+    pub acceleration: f64,
+    pub deceleration: f64,
+    #[serde(default)]
+    pub torque_limit: Option<f64>,
+}
+
+/// How much to trust a position estimate, based on the age of the last position read back from
+/// the controller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub enum Confidence {
+    None,
+    Low,
+    Medium,
+    High,
+}
+
+impl Confidence {
+    fn from_age(age: Duration) -> Self {
+        if age < Duration::from_millis(250) {
+            Confidence::High
+        } else if age < Duration::from_secs(2) {
+            Confidence::Medium
+        } else if age < Duration::from_secs(10) {
+            Confidence::Low
+        } else {
+            Confidence::None
+        }
+    }
+}
+
+/// Commanded and measured velocity in user units per second. Fields the firmware leaves empty
+/// in the reply are `None`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct VelocityDetail {
+    pub commanded: Option<f64>,
+    pub actual: Option<f64>,
+}
+
+/// Largest values seen while sampling during `wait_for_move`. These are maintained by the
+/// client, not read from the controller, so a peak between two polls is missed: accuracy
+/// depends on the poll interval passed to `wait_for_move`, and each sample costs two round
+/// trips that stretch the interval further. Torque spikes are short, mostly at the start and
+/// end of a move, so the torque peak in particular is a lower bound unless the poll is fast.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct PeakStats {
+    /// Highest measured torque, as a percentage of the drive's peak torque like `get_torque`.
+    pub torque: Option<f64>,
+    /// Highest measured speed, in user units.
+    pub velocity: Option<f64>,
+    /// Largest difference between commanded and measured velocity.
+    pub velocity_error: Option<f64>,
+}
+
+impl PeakStats {
+    fn record(&mut self, detail: VelocityDetail, torque: f64) {
+        fn raise(peak: &mut Option<f64>, value: f64) {
+            *peak = Some(peak.map_or(value, |peak| peak.max(value)));
+        }
+        raise(&mut self.torque, torque.abs());
+        if let Some(actual) = detail.actual {
+            raise(&mut self.velocity, actual.abs());
+            if let Some(commanded) = detail.commanded {
+                raise(&mut self.velocity_error, (commanded - actual).abs());
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum MotionPhase {
+    Idle,
+    Accelerating,
+    Cruising,
+    Decelerating,
+}
+
+/// Where the current move is, see `move_progress`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct MoveProgress {
+    /// Time since the move was commanded.
+    pub elapsed: Duration,
+    pub position: f64,
+    pub start: f64,
+    pub target: f64,
+    /// Share of the distance from `start` to `target` covered so far, `0.0..=1.0`.
+    pub fraction: f64,
+    /// Time left according to the commanded profile; `None` for a jog or an unknown velocity.
+    pub remaining: Option<Duration>,
+}
+
+/// A motor's acceleration and deceleration, see `get_ramp`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum Ramp {
+    Symmetric(f64),
+    Asymmetric {
+        acceleration: f64,
+        deceleration: f64,
+    },
+}
+
+/// How the drive signals on its HLFB line, matching ClearCore's `MotorDriver::HlfbModes`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HlfbMode {
+    /// Plain on/off, e.g. "Servo On" or "In Range".
+    Static,
+    /// PWM duty cycle carrying a measurement such as torque, asserted-high otherwise.
+    HasPwm,
+    /// Bipolar PWM, e.g. signed measured torque.
+    HasBipolarPwm,
 }
 
-#[derive(Debug, PartialOrd, PartialEq, Serialize)]
+impl HlfbMode {
+    fn code(self) -> isize {
+        match self {
+            HlfbMode::Static => 0,
+            HlfbMode::HasPwm => 1,
+            HlfbMode::HasBipolarPwm => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Limit {
+    Positive,
+    Negative,
+}
+
+/// Why a move stopped.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum MoveOutcome {
+    Completed,
+    /// `stop` or `abrupt_stop` was issued while the move was running.
+    Stopped,
+    /// The motor faulted; carries the alerts latched when the move ended.
+    Faulted(Vec<AlertKind>),
+    /// The controller cancelled the move at a travel limit.
+    LimitHit(Limit),
+    /// The move finished away from its target, e.g. clamped to a firmware soft limit. Only
+    /// reported with `set_clamp_check` enabled.
+    Clamped {
+        requested: f64,
+        actual: f64,
+    },
+    Timeout,
+}
+
+/// One bit of the ClearCore motor alert register (`MotorDriver::AlertRegMotor`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum AlertKind {
+    /// A move was commanded while another alert was active.
+    MotionCanceledInAlert,
+    MotionCanceledPositiveLimit,
+    MotionCanceledNegativeLimit,
+    /// The E-stop sensor input tripped.
+    MotionCanceledSensorEStop,
+    MotionCanceledMotorDisabled,
+    /// The drive asserted its fault output (HLFB), e.g. on a shutdown for over-temperature or
+    /// a tracking error; the drive's own software tells which.
+    MotorFaulted,
+    /// A bit this crate does not know, as its mask.
+    Unknown(u32),
+}
+
+impl AlertKind {
+    const KNOWN: [AlertKind; 6] = [
+        AlertKind::MotionCanceledInAlert,
+        AlertKind::MotionCanceledPositiveLimit,
+        AlertKind::MotionCanceledNegativeLimit,
+        AlertKind::MotionCanceledSensorEStop,
+        AlertKind::MotionCanceledMotorDisabled,
+        AlertKind::MotorFaulted,
+    ];
+
+    /// Every alert set in `register`, lowest bit first.
+    pub fn decode(register: u32) -> Vec<AlertKind> {
+        (0..u32::BITS)
+            .filter(|bit| register & (1 << bit) != 0)
+            .map(|bit| {
+                Self::KNOWN
+                    .get(bit as usize)
+                    .copied()
+                    .unwrap_or(AlertKind::Unknown(1 << bit))
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialOrd, PartialEq, Eq, Serialize)]
 pub enum Status {
     Disabled,
     Enabling,
@@ -26,11 +345,93 @@ pub enum Status {
     Moving,
 }
 
+/// Status from a `GS` reply.
+fn parse_status(reply: &[u8]) -> Result<Status> {
+    check_reply(reply)?;
+    match reply[REPLY_IDX] {
+        48 => Ok(Status::Disabled),
+        49 => Ok(Status::Enabling),
+        50 => Ok(Status::Faulted),
+        51 => Ok(Status::Ready),
+        52 => Ok(Status::Moving),
+        code => Err(ClearCoreError::UnknownStatus(code).into()),
+    }
+}
+
+/// Rejects a negative, infinite or NaN limit before it is truncated into counts.
+fn check_magnitude(parameter: &'static str, value: f64) -> Result<()> {
+    if value.is_finite() && value >= 0. {
+        Ok(())
+    } else {
+        Err(ClearCoreError::InvalidParameter { parameter, value }.into())
+    }
+}
+
+#[derive(Clone)]
+struct Brake {
+    output: DigitalOutput,
+    engaged: Arc<AtomicBool>,
+}
+
+/// Stops the motor if dropped while still armed, i.e. when the operation owning it is cancelled
+/// before it finished. Arm it once the controller has accepted the motion, so a rejected command
+/// is not followed by a stop.
+struct StopGuard<'a> {
+    motor: Option<&'a ClearCoreMotor>,
+}
+
+impl<'a> StopGuard<'a> {
+    fn arm(motor: &'a ClearCoreMotor) -> Self {
+        Self { motor: Some(motor) }
+    }
+
+    fn disarm(mut self) {
+        self.motor = None;
+    }
+}
+
+impl Drop for StopGuard<'_> {
+    fn drop(&mut self) {
+        let Some(motor) = self.motor.take() else {
+            return;
+        };
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            error!(
+                "Cannot stop motor {} after cancellation: no runtime",
+                motor.id
+            );
+            return;
+        };
+        let motor = motor.clone();
+        runtime.spawn(async move {
+            if let Err(e) = motor.stop().await {
+                error!("Failed to stop motor {} after cancellation: {e}", motor.id);
+            }
+        });
+    }
+}
+
+type MoveCallback = Box<dyn Fn(MoveOutcome) + Send>;
+
 #[derive(Clone)]
 pub struct ClearCoreMotor {
     pub id: u8,
     prefix: [u8; 3],
-    scale: usize,
+    /// Counts per user unit as `f64` bits, shared by all clones, see `set_scale`.
+    scale: Arc<AtomicU64>,
+    inverted: bool,
+    brake: Option<Brake>,
+    homing: HomingPolicy,
+    settle_delay: Duration,
+    min_cycle_interval: Duration,
+    soft_limits: Option<(f64, f64)>,
+    limit_mode: LimitMode,
+    default_profile: Option<MotionProfile>,
+    position_lost: Arc<AtomicBool>,
+    homing_lock: Arc<tokio::sync::Mutex<()>>,
+    state: Arc<Mutex<MotionState>>,
+    move_callbacks: Arc<Mutex<Vec<MoveCallback>>>,
+    faults: Option<FaultReporter>,
     drive_sender: Sender<Message>,
 }
 
@@ -47,160 +448,782 @@ impl ClearCoreMotor {
         ClearCoreMotor {
             id,
             prefix,
-            scale,
+            scale: Arc::new(AtomicU64::new((scale as f64).to_bits())),
+            inverted: false,
+            brake: None,
+            homing: HomingPolicy::Manual,
+            settle_delay: Duration::ZERO,
+            min_cycle_interval: Duration::ZERO,
+            soft_limits: None,
+            limit_mode: LimitMode::Reject,
+            default_profile: None,
+            position_lost: Arc::new(AtomicBool::new(false)),
+            homing_lock: Arc::new(tokio::sync::Mutex::new(())),
+            state: Arc::new(Mutex::new(MotionState::default())),
+            move_callbacks: Arc::new(Mutex::new(Vec::new())),
+            faults: None,
             drive_sender,
         }
     }
 
+    pub(crate) fn with_fault_reporter(mut self, faults: FaultReporter) -> Self {
+        self.faults = Some(faults);
+        self
+    }
+
+    pub fn with_homing_policy(mut self, homing: HomingPolicy) -> Self {
+        self.homing = homing;
+        self
+    }
+
+    /// Flips the motor's direction, e.g. for one of two axes mounted in mirror image: positions,
+    /// moves, jog speeds and velocity readings are negated between user units and counts.
+    /// Velocity and acceleration limits are magnitudes and stay as they are.
+    pub fn with_inverted(mut self) -> Self {
+        self.inverted = true;
+        self
+    }
+
+    /// Fixed time the blocking moves wait after a move completes before returning, for the
+    /// mechanism to stop ringing. Unlike `wait_for_in_position` it does not consult HLFB: it is
+    /// a plain delay. Zero by default.
+    pub fn with_settle_delay(mut self, settle_delay: Duration) -> Self {
+        self.settle_delay = settle_delay;
+        self
+    }
+
+    /// Shortest time allowed between one enable or disable and the next, to protect the drive
+    /// from a supervisor thrashing its enable line. One requested sooner fails with
+    /// `ClearCoreError::CyclingTooFast` without being sent. Zero (no limit) by default.
+    pub fn with_min_cycle_interval(mut self, interval: Duration) -> Self {
+        self.min_cycle_interval = interval;
+        self
+    }
+
+    /// Client-side travel limits in user units; the bounds may be given in either order.
+    /// `absolute_move` and `relative_move` check their target against them before sending, see
+    /// `with_limit_mode`. Jogs and homing are not checked.
+    pub fn with_soft_limits(mut self, min: f64, max: f64) -> Self {
+        self.soft_limits = Some((min.min(max), min.max(max)));
+        self
+    }
+
+    pub fn with_limit_mode(mut self, mode: LimitMode) -> Self {
+        self.limit_mode = mode;
+        self
+    }
+
+    /// Profile `recover` restores; motors made by `ControllerHandle::with_config` get the one
+    /// `ControllerConfig::with_motor_profile` gives them.
+    pub fn with_default_profile(mut self, profile: MotionProfile) -> Self {
+        self.default_profile = Some(profile);
+        self
+    }
+
+    /// `(min, max)` as set by `with_soft_limits`.
+    pub fn soft_limits(&self) -> Option<(f64, f64)> {
+        self.soft_limits
+    }
+
+    /// Span between the soft limits.
+    pub fn travel_range(&self) -> Option<f64> {
+        self.soft_limits.map(|(min, max)| max - min)
+    }
+
+    /// `target` if it is within the soft limits, otherwise the limit it crosses under
+    /// `LimitMode::Clamp` or an `OutOfRange` error.
+    fn limit_target(&self, target: f64) -> Result<f64> {
+        let Some((min, max)) = self.soft_limits else {
+            return Ok(target);
+        };
+        let limit = target.clamp(min, max);
+        if limit == target {
+            return Ok(target);
+        }
+        match self.limit_mode {
+            LimitMode::Reject => Err(ClearCoreError::OutOfRange {
+                requested: target,
+                limit,
+            })
+            .with_context(|| format!("motor {}", self.id)),
+            LimitMode::Clamp => {
+                warn!(
+                    "Motor {} target {target} clamped to soft limit {limit}",
+                    self.id
+                );
+                Ok(limit)
+            }
+        }
+    }
+
+    /// Where a relative move starts from, read fresh: the commanded target is no base during a
+    /// jog, which has none. `OutOfRange` if the position is not finite, as no move from it can
+    /// be checked against the limits.
+    async fn relative_base(&self, (min, max): (f64, f64)) -> Result<f64> {
+        let base = self.get_position().await?;
+        if !base.is_finite() {
+            let limit = if base > 0. { max } else { min };
+            return Err(ClearCoreError::OutOfRange {
+                requested: base,
+                limit,
+            })
+            .with_context(|| format!("motor {} position", self.id));
+        }
+        Ok(base)
+    }
+
+    fn check_cycle_interval(&self) -> Result<()> {
+        let Some(last) = self.state().last_transition else {
+            return Ok(());
+        };
+        let wait = self.min_cycle_interval.saturating_sub(last.elapsed());
+        if !wait.is_zero() {
+            return Err(ClearCoreError::CyclingTooFast(wait).into());
+        }
+        Ok(())
+    }
+
+    /// Flags the position register as untrustworthy, e.g. after a controller reboot. With
+    /// `HomingPolicy::RehomeOnPositionLoss` the next move re-homes first.
+    pub fn mark_position_lost(&self) {
+        self.position_lost.store(true, Ordering::SeqCst);
+    }
+
+    pub fn position_lost(&self) -> bool {
+        self.position_lost.load(Ordering::SeqCst)
+    }
+
+    async fn ensure_homed(&self) -> Result<()> {
+        let HomingPolicy::RehomeOnPositionLoss(routine) = &self.homing else {
+            return Ok(());
+        };
+        if !self.position_lost() {
+            return Ok(());
+        }
+        let _homing = self.homing_lock.lock().await;
+        if self.position_lost() {
+            // The routine gets a handle without the policy so its own moves go straight out.
+            let motor = self.clone().with_homing_policy(HomingPolicy::Manual);
+            routine(motor).await?;
+            self.position_lost.store(false, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    fn state(&self) -> MutexGuard<'_, MotionState> {
+        self.state.lock().expect("motion state poisoned")
+    }
+
+    /// Best guess of the current position from the last position read back and the moves
+    /// commanded since, for use while the controller cannot be reached. Every successful
+    /// `get_position` re-anchors the estimate.
+    pub fn estimated_position(&self) -> (f64, Confidence) {
+        let now = Instant::now();
+        let state = self.state();
+        let confidence = state.last_reading.map_or(Confidence::None, |reading| {
+            Confidence::from_age(now.duration_since(reading.at))
+        });
+        match state.estimate(now) {
+            Some(position) => (position, confidence),
+            None => (0., Confidence::None),
+        }
+    }
+
+    /// Re-anchors `estimated_position` on the controller's position register, e.g. after a
+    /// reconnect, and forgets the commanded move unless the motor is still moving. The client
+    /// does this for every motor whenever it reconnects.
+    pub async fn reconcile(&self) -> Result<f64> {
+        let moving = self.get_status().await? == Status::Moving;
+        let position = self.get_position().await?;
+        if !moving {
+            self.state().commanded_move = None;
+        }
+        Ok(position)
+    }
+
+    /// A handle to the same motor that sends through `drive_sender`.
+    pub(crate) fn with_drive_sender(&self, drive_sender: Sender<Message>) -> Self {
+        Self {
+            drive_sender,
+            ..self.clone()
+        }
+    }
+
+    /// Attaches a holding brake; it is assumed engaged (output de-energized) until released.
+    pub fn with_brake(mut self, output: DigitalOutput) -> Self {
+        self.brake = Some(Brake {
+            output,
+            engaged: Arc::new(AtomicBool::new(true)),
+        });
+        self
+    }
+
+    fn get_brake(&self) -> Result<&Brake> {
+        self.brake
+            .as_ref()
+            .ok_or_else(|| anyhow!("motor {} has no brake configured", self.id))
+    }
+
+    /// Whether the motor was built with a holding brake, see `MotorBuilder::with_brake`.
+    pub fn has_brake(&self) -> bool {
+        self.brake.is_some()
+    }
+
+    /// Brake state as last commanded by this client; the output itself is not read back.
+    pub fn brake_engaged(&self) -> Result<bool> {
+        Ok(self.get_brake()?.engaged.load(Ordering::SeqCst))
+    }
+
+    pub async fn engage_brake(&self) -> Result<()> {
+        let brake = self.get_brake()?;
+        brake.output.set_state(false).await?;
+        brake.engaged.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    pub async fn release_brake(&self) -> Result<()> {
+        let brake = self.get_brake()?;
+        brake.output.set_state(true).await?;
+        brake.engaged.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Enables the drive and, once it holds torque, releases the brake if one is configured.
     pub async fn enable(&self) -> Result<()> {
-        let enable_cmd = [2, b'M', self.id + 48, b'E', b'N', 13];
-        let resp = self.write(enable_cmd.as_ref()).await;
+        self.check_cycle_interval()?;
+        let enable_cmd = codec::encode_enable(&self.prefix);
+        let resp = self.write(&enable_cmd).await?;
         check_reply(&resp)?;
+        self.state().last_transition = Some(Instant::now());
         let mut tick_interval = tokio::time::interval(Duration::from_millis(250));
         tick_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
         while self.get_status().await? == Status::Enabling {
             tick_interval.tick().await;
         }
         if self.get_status().await? == Status::Faulted {
-            Err(anyhow!("motor faulted".to_string()))
-        } else {
-            Ok(())
+            return Err(ClearCoreError::Faulted).with_context(|| format!("motor {}", self.id));
+        }
+        if self.brake.is_some() {
+            self.release_brake().await?;
         }
+        Ok(())
     }
 
+    /// Engages the brake, if one is configured, before removing drive power.
     pub async fn disable(&self) -> Result<()> {
-        let enable_cmd = [2, b'M', self.id + 48, b'D', b'E', 13];
-        let resp = self.write(enable_cmd.as_ref()).await;
+        self.check_cycle_interval()?;
+        if self.brake.is_some() {
+            self.engage_brake().await?;
+        }
+        let disable_cmd = codec::encode_disable(&self.prefix);
+        let resp = self.write(&disable_cmd).await?;
         check_reply(resp.as_ref())?;
+        self.state().last_transition = Some(Instant::now());
         Ok(())
     }
 
-    pub async fn absolute_move(&self, position: f64) -> Result<()> {
-        let position = num_to_bytes((position * (self.scale as f64)).trunc() as isize);
-        let mut msg: Vec<u8> = Vec::with_capacity(position.len() + self.prefix.len() + 1);
-        msg.extend_from_slice(self.prefix.as_slice());
-        msg.extend_from_slice(b"AM");
-        msg.extend_from_slice(position.as_slice());
-        msg.push(13);
-        let resp = self.write(msg.as_slice()).await;
+    pub async fn absolute_move(&self, position: impl IntoCounts) -> Result<()> {
+        self.ensure_homed().await?;
+        let mut counts = self.counts_of(position);
+        let requested = counts as f64 / self.signed_scale();
+        let limited = self.limit_target(requested)?;
+        if limited != requested {
+            counts = self.counts_of(limited);
+        }
+        let target = counts as f64 / self.signed_scale();
+        let msg = codec::encode_absolute_move(&self.prefix, counts);
+        self.expect_motion();
+        let resp = self.write(msg.as_slice()).await?;
         check_reply(&resp)?;
+        let mut state = self.state();
+        let velocity = state.velocity.unwrap_or(0.);
+        state.record_move(target, velocity);
+        Ok(())
+    }
+
+    /// Counts per user unit.
+    pub fn scale(&self) -> f64 {
+        f64::from_bits(self.scale.load(Ordering::SeqCst))
+    }
+
+    /// Replaces the counts-per-unit scale for this motor and all its clones, e.g. after a
+    /// calibration; every later command and read converts with the new value. A move already
+    /// commanded runs on in counts, so it ends where the old scale put it, and the client-side
+    /// position estimate and ramp settings stay in old units until the next read or set.
+    /// Velocity and acceleration on the controller are in counts too: set them again to keep the
+    /// same speed in user units.
+    pub fn set_scale(&self, scale: f64) -> Result<()> {
+        if !(scale.is_finite() && scale > 0.) {
+            return Err(anyhow!("invalid scale {scale}"));
+        }
+        self.scale.store(scale.to_bits(), Ordering::SeqCst);
         Ok(())
     }
 
-    pub async fn relative_move(&self, position: f64) -> Result<()> {
-        let position = num_to_bytes((position * (self.scale as f64)).trunc() as isize);
-        let mut msg: Vec<u8> = Vec::with_capacity(position.len() + self.prefix.len() + 1);
-        msg.extend_from_slice(self.prefix.as_slice());
-        msg.extend_from_slice(b"RM");
-        msg.extend_from_slice(position.as_slice());
-        msg.push(13);
-        let resp = self.write(msg.as_slice()).await;
+    /// Counts per user unit in the motor's direction, negative when inverted.
+    fn signed_scale(&self) -> f64 {
+        if self.inverted {
+            -self.scale()
+        } else {
+            self.scale()
+        }
+    }
+
+    /// Converts with the unsigned scale, then applies the motor's direction, see `IntoCounts`.
+    fn counts_of(&self, position: impl IntoCounts) -> isize {
+        let counts = position.into_counts(self.scale());
+        if self.inverted { -counts } else { counts }
+    }
+
+    /// Smallest move the motor can make, in user units; positions are truncated to whole counts.
+    pub fn min_step(&self) -> f64 {
+        1. / self.scale()
+    }
+
+    /// Moves to the midpoint of the soft limits, e.g. to park between cycles; does not wait for
+    /// the move to finish. Errors if no soft limits are configured.
+    pub async fn move_to_center(&self) -> Result<()> {
+        let (min, max) = self
+            .soft_limits
+            .ok_or_else(|| anyhow!("motor {} has no soft limits to center between", self.id))?;
+        self.absolute_move(min + (max - min) / 2.).await
+    }
+
+    pub async fn relative_move(&self, position: impl IntoCounts) -> Result<()> {
+        self.ensure_homed().await?;
+        let mut counts = self.counts_of(position);
+        if let Some(limits) = self.soft_limits {
+            let base = self.relative_base(limits).await?;
+            let requested = base + counts as f64 / self.signed_scale();
+            let limited = self.limit_target(requested)?;
+            if limited != requested {
+                counts = self.counts_of(limited - base);
+            }
+        }
+        if counts == 0 {
+            warn!(
+                "Relative move on motor {} truncates to zero counts (min step {})",
+                self.id,
+                self.min_step()
+            );
+        }
+        let delta = counts as f64 / self.signed_scale();
+        let msg = codec::encode_relative_move(&self.prefix, counts);
+        self.expect_motion();
+        let resp = self.write(msg.as_slice()).await?;
         check_reply(&resp)?;
+        let mut state = self.state();
+        if let Some(current) = state.estimate(Instant::now()) {
+            let velocity = state.velocity.unwrap_or(0.);
+            state.record_move(current + delta, velocity);
+        }
         Ok(())
     }
 
     pub async fn jog(&self, speed: f64) -> Result<()> {
-        let speed = num_to_bytes((speed * (self.scale as f64)).trunc() as isize);
-        let mut msg: Vec<u8> = Vec::with_capacity(speed.len() + self.prefix.len() + 1);
-        msg.extend_from_slice(self.prefix.as_slice());
-        msg.extend_from_slice(b"JG");
-        msg.extend_from_slice(speed.as_slice());
-        msg.push(13);
-        let resp = self.write(msg.as_slice()).await;
+        let counts = (speed * self.signed_scale()).trunc() as isize;
+        let msg = codec::encode_jog(&self.prefix, counts);
+        self.expect_motion();
+        let resp = self.write(msg.as_slice()).await?;
         check_reply(&resp)?;
+        let jog_speed = counts as f64 / self.signed_scale();
+        let mut state = self.state();
+        state.record_move(f64::INFINITY.copysign(speed), jog_speed.abs());
+        state.jog_speed = Some(jog_speed);
         Ok(())
     }
 
+    /// Jogs at `speed` until `input` sees `edge`, e.g. to drive onto a switch, then stops at the
+    /// configured deceleration. The input is read before the jog starts; for a rising or falling
+    /// edge it must not already be at the level the edge leads to, or the call fails without
+    /// moving. If the returned future is dropped once the jog is running (e.g. by a timeout) or
+    /// reading the input fails, a `stop` is issued from a spawned task, as with
+    /// `absolute_move_blocking`.
+    pub async fn jog_until(
+        &self,
+        speed: f64,
+        input: &DigitalInput,
+        edge: Edge,
+        poll: Duration,
+    ) -> Result<()> {
+        let level = input.read().await?;
+        match edge {
+            Edge::Rising if level => return Err(anyhow!("input is already high")),
+            Edge::Falling if !level => return Err(anyhow!("input is already low")),
+            _ => {}
+        }
+        self.jog(speed).await?;
+        let guard = StopGuard::arm(self);
+        input.wait_for_edge_from(level, edge, poll).await?;
+        guard.disarm();
+        self.stop().await
+    }
+
+    /// Has `get_status` report the motor moving while no move, jog, stop or homing from this
+    /// client is outstanding, e.g. a runaway or a command from elsewhere, as
+    /// `FaultKind::UnexpectedMotion` on the controller's fault bus. Run
+    /// `ControllerHandle::monitor_faults` to have status read in the background.
+    pub fn set_unexpected_motion_check(&self, enabled: bool) {
+        self.state().motion_check = enabled;
+    }
+
+    /// Counts a command that may leave the motor moving, before it is sent, so a status read
+    /// racing it does not see unexplained motion.
+    fn expect_motion(&self) {
+        self.state().motion_commands += 1;
+    }
+
+    /// Speed of the jog in progress as sent to the controller, i.e. after truncation to whole
+    /// counts per second; `None` once it is stopped or replaced by a move. Tracked client-side.
+    pub fn commanded_jog_speed(&self) -> Option<f64> {
+        self.state().jog_speed
+    }
+
     pub async fn abrupt_stop(&self) -> Result<()> {
-        let stop_cmd = [2, b'M', self.id + 48, b'A', b'S', 13];
-        let resp = self.write(stop_cmd.as_ref()).await;
+        let stop_cmd = codec::encode_abrupt_stop(&self.prefix);
+        self.expect_motion();
+        let resp = self.write(&stop_cmd).await?;
         check_reply(&resp)?;
+        self.state().record_stop();
         Ok(())
     }
 
     pub async fn stop(&self) -> Result<()> {
-        let stop_cmd = [2, b'M', self.id + 48, b'S', b'T', 13];
-        let resp = self.write(stop_cmd.as_ref()).await;
+        let stop_cmd = codec::encode_stop(&self.prefix);
+        self.expect_motion();
+        let resp = self.write(&stop_cmd).await?;
         check_reply(&resp)?;
+        self.state().record_stop();
         Ok(())
     }
 
     pub async fn set_position(&self, position: isize) -> Result<()> {
-        let pos = num_to_bytes(position * self.scale as isize);
-        let mut msg: Vec<u8> = Vec::with_capacity(pos.len() + self.prefix.len() + 1);
-        msg.extend_from_slice(self.prefix.as_slice());
-        msg.extend_from_slice(b"SP");
-        msg.extend_from_slice(pos.as_slice());
-        msg.push(13);
-        let resp = self.write(msg.as_slice()).await;
+        let msg = codec::encode_set_position(
+            &self.prefix,
+            (position as f64 * self.signed_scale()).trunc() as isize,
+        );
+        let resp = self.write(msg.as_slice()).await?;
         check_reply(&resp)?;
+        let mut state = self.state();
+        state.commanded_move = None;
+        state.record_reading(position as f64);
         Ok(())
     }
 
-    pub async fn set_velocity(&self, mut velocity: f64) -> Result<()> {
-        if velocity < 0. {
-            velocity = 0.;
-        }
-        let vel = num_to_bytes((velocity * (self.scale as f64)).trunc() as isize);
-        let mut msg: Vec<u8> = Vec::with_capacity(vel.len() + self.prefix.len() + 1);
-        msg.extend_from_slice(self.prefix.as_slice());
-        msg.extend_from_slice(b"SV");
-        msg.extend_from_slice(vel.as_slice());
-        msg.push(13);
-        let resp = self.write(msg.as_slice()).await;
+    /// Velocity limit for moves, in user units per second. Fails with
+    /// `ClearCoreError::InvalidParameter` unless finite and non-negative; the direction of a move
+    /// comes from its target, not from the sign of the velocity.
+    pub async fn set_velocity(&self, velocity: f64) -> Result<()> {
+        check_magnitude("velocity", velocity)?;
+        let msg =
+            codec::encode_set_velocity(&self.prefix, (velocity * self.scale()).trunc() as isize);
+        let resp = self.write(msg.as_slice()).await?;
         check_reply(&resp)?;
+        self.state().velocity = Some(velocity);
         Ok(())
     }
 
+    /// Acceleration limit in user units per second squared; must be finite and non-negative,
+    /// as for `set_velocity`.
     pub async fn set_acceleration(&self, acceleration: f64) -> Result<()> {
-        let accel = num_to_bytes((acceleration * (self.scale as f64)).trunc() as isize);
-        let mut msg: Vec<u8> = Vec::with_capacity(accel.len() + self.prefix.len() + 1);
-        msg.extend_from_slice(self.prefix.as_slice());
-        msg.extend_from_slice(b"SA");
-        msg.extend_from_slice(accel.as_slice());
-        msg.push(13);
-        let resp = self.write(msg.as_slice()).await;
+        check_magnitude("acceleration", acceleration)?;
+        let msg = codec::encode_set_acceleration(
+            &self.prefix,
+            (acceleration * self.scale()).trunc() as isize,
+        );
+        let resp = self.write(msg.as_slice()).await?;
         check_reply(&resp)?;
+        self.state().acceleration = Some(acceleration);
         Ok(())
     }
 
+    /// Deceleration limit in user units per second squared; must be finite and non-negative,
+    /// as for `set_velocity`.
     pub async fn set_deceleration(&self, deceleration: f64) -> Result<()> {
-        let accel = num_to_bytes((deceleration * (self.scale as f64)).trunc() as isize);
-        let mut msg: Vec<u8> = Vec::with_capacity(accel.len() + self.prefix.len() + 1);
-        msg.extend_from_slice(self.prefix.as_slice());
-        msg.extend_from_slice(b"SD");
-        msg.extend_from_slice(accel.as_slice());
-        msg.push(13);
-        let resp = self.write(msg.as_slice()).await;
+        check_magnitude("deceleration", deceleration)?;
+        let msg = codec::encode_set_deceleration(
+            &self.prefix,
+            (deceleration * self.scale()).trunc() as isize,
+        );
+        let resp = self.write(msg.as_slice()).await?;
         check_reply(&resp)?;
+        self.state().deceleration = Some(deceleration);
+        Ok(())
+    }
+
+    /// Velocity as last set through this client, without a round trip; `None` if not set since
+    /// connecting. `get_velocity` asks the controller instead.
+    pub fn commanded_velocity(&self) -> Option<f64> {
+        self.state().velocity
+    }
+
+    /// Sets acceleration and deceleration to the same `rate`, sending both without waiting for
+    /// the first reply.
+    pub async fn set_ramp(&self, rate: f64) -> Result<()> {
+        tokio::try_join!(self.set_acceleration(rate), self.set_deceleration(rate))?;
+        Ok(())
+    }
+
+    /// Acceleration and deceleration as last set through this client. Acceleration not set since
+    /// connecting is read with `get_acceleration`; deceleration has no query in the firmware, so
+    /// this errors if it has not been set since connecting.
+    pub async fn get_ramp(&self) -> Result<Ramp> {
+        let (acceleration, deceleration) = {
+            let state = self.state();
+            (state.acceleration, state.deceleration)
+        };
+        let deceleration = deceleration
+            .ok_or_else(|| anyhow!("motor {} deceleration not set since connecting", self.id))?;
+        let acceleration = match acceleration {
+            Some(acceleration) => acceleration,
+            None => self.get_acceleration().await?,
+        };
+        if acceleration == deceleration {
+            Ok(Ramp::Symmetric(acceleration))
+        } else {
+            Ok(Ramp::Asymmetric {
+                acceleration,
+                deceleration,
+            })
+        }
+    }
+
+    /// Limits motor torque to `percent` of the drive's peak torque.
+    pub async fn set_torque_limit(&self, percent: f64) -> Result<()> {
+        let msg = codec::encode_set_torque_limit(&self.prefix, percent.trunc() as isize);
+        let resp = self.write(msg.as_slice()).await?;
+        check_reply(&resp)?;
+        Ok(())
+    }
+
+    /// Gets a faulted motor back to `Status::Ready`, e.g. from an operator's reset button:
+    /// clears alerts, enables (releasing the brake, if any) and applies the default profile, if
+    /// one is set. If the motor does not come back the error carries its alert register.
+    pub async fn recover(&self) -> Result<()> {
+        self.clear_alerts().await?;
+        if let Err(e) = self.enable().await {
+            let alerts = self.alerts_for_context().await;
+            return Err(e.context(format!(
+                "motor {}: recovery failed, alerts {alerts}",
+                self.id
+            )));
+        }
+        if let Some(profile) = &self.default_profile {
+            self.apply_profile(profile).await?;
+        }
+        match self.get_status().await? {
+            Status::Ready => Ok(()),
+            status => {
+                let alerts = self.alerts_for_context().await;
+                let context = format!(
+                    "motor {}: {status:?} after recovery, alerts {alerts}",
+                    self.id
+                );
+                match status {
+                    Status::Faulted => Err(ClearCoreError::Faulted).context(context),
+                    _ => Err(anyhow!(context)),
+                }
+            }
+        }
+    }
+
+    /// Alert register for an error message, read best-effort so that a failure to read it (e.g.
+    /// `Unsupported` on stock firmware) does not hide the error being reported.
+    async fn alerts_for_context(&self) -> String {
+        match self.get_alert_register().await {
+            Ok(alerts) => format!("{alerts:#x}"),
+            Err(e) => format!("unavailable ({e})"),
+        }
+    }
+
+    /// Sends every setting of the profile without waiting for the previous reply.
+    pub async fn apply_profile(&self, profile: &MotionProfile) -> Result<()> {
+        tokio::try_join!(
+            self.set_velocity(profile.velocity),
+            self.set_acceleration(profile.acceleration),
+            self.set_deceleration(profile.deceleration),
+            async {
+                match profile.torque_limit {
+                    Some(limit) => self.set_torque_limit(limit).await,
+                    None => Ok(()),
+                }
+            },
+        )?;
         Ok(())
     }
 
     pub async fn get_status(&self) -> Result<Status> {
-        let status_cmd = [2, b'M', self.id + 48, b'G', b'S', 13];
-        let res = self.write(status_cmd.as_slice()).await;
-        match res[3] {
-            48 => Ok(Status::Disabled),
-            49 => Ok(Status::Enabling),
-            50 => Ok(Status::Faulted),
-            51 => Ok(Status::Ready),
-            52 => Ok(Status::Moving),
-            _ => Err(anyhow!("unknown status".to_string()),
-            ),
+        let status_cmd = codec::encode_get_status(&self.prefix);
+        let commands = self.state().motion_commands;
+        let res = self.write(&status_cmd).await?;
+        let status = parse_status(&res)?;
+        let unexpected_motion = self
+            .state()
+            .observe_status(status == Status::Moving, commands);
+        if let Some(faults) = &self.faults {
+            faults.update(match status {
+                Status::Faulted => Some(FaultKind::MotorFaulted),
+                Status::Moving if unexpected_motion => Some(FaultKind::UnexpectedMotion),
+                _ => None,
+            });
         }
+        Ok(status)
+    }
+
+    /// Reads the status now and then every `poll` in a background task, publishing it only when
+    /// it changes. The task ends once every receiver is dropped or the client stops; failed
+    /// reads in between are logged and the last status kept.
+    pub async fn status_stream(&self, poll: Duration) -> Result<watch::Receiver<Status>> {
+        let (tx, rx) = watch::channel(self.get_status().await?);
+        let motor = self.clone();
+        tokio::spawn(async move {
+            let mut tick_interval = tokio::time::interval(poll);
+            tick_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+            tick_interval.tick().await;
+            loop {
+                tokio::select! {
+                    _ = tx.closed() => break,
+                    _ = tick_interval.tick() => {}
+                }
+                match motor.get_status().await {
+                    Ok(status) => {
+                        tx.send_if_modified(|current| {
+                            let changed = *current != status;
+                            *current = status;
+                            changed
+                        });
+                    }
+                    Err(e)
+                        if e.downcast_ref::<ClearCoreError>()
+                            == Some(&ClearCoreError::Disconnected) =>
+                    {
+                        break;
+                    }
+                    Err(e) => warn!("Motor {} status stream: {e:#}", motor.id),
+                }
+            }
+        });
+        Ok(rx)
     }
 
     pub async fn get_position(&self) -> Result<f64> {
-        let get_pos_cmd = [2, b'M', self.id + 48, b'G', b'P', 13];
-        let res = self.write(get_pos_cmd.as_slice()).await;
+        let get_pos_cmd = codec::encode_get_position(&self.prefix);
+        let res = self.write(&get_pos_cmd).await?;
+        let position = (reply_int(&res)? as f64) / self.signed_scale();
+        self.state().record_reading(position);
+        Ok(position)
+    }
+
+    /// Velocity limit moves run at (`M<id>GV`), as the controller has it from `set_velocity`.
+    /// For how fast the motor is turning, see `get_velocity_detail`.
+    pub async fn get_velocity(&self) -> Result<f64> {
+        let velocity_cmd = codec::encode_get_velocity(&self.prefix);
+        let res = self.write(&velocity_cmd).await?;
+        Ok((reply_int(&res)? as f64) / self.scale())
+    }
+
+    /// Acceleration limit (`M<id>GA`), as the controller has it from `set_acceleration`.
+    pub async fn get_acceleration(&self) -> Result<f64> {
+        let acceleration_cmd = codec::encode_get_acceleration(&self.prefix);
+        let res = self.write(&acceleration_cmd).await?;
+        Ok((reply_int(&res)? as f64) / self.scale())
+    }
+
+    /// Measured torque (`M<id>GT`) as a signed percentage of the drive's peak torque, the same
+    /// unit as `set_torque_limit`; the reply is in tenths of a percent. Not affected by `scale`,
+    /// which only applies to positions and their derivatives. Reads 0 on drives whose HLFB is
+    /// not set up to report torque, see `set_hlfb_mode`.
+    pub async fn get_torque(&self) -> Result<f64> {
+        let torque_cmd = codec::encode_get_torque(&self.prefix);
+        let res = self.write(&torque_cmd).await?;
+        Ok(reply_int(&res)? as f64 / 10.)
+    }
+
+    /// Reads both velocities in one round trip (`M<id>VD`, reply `<commanded>,<actual>`).
+    pub async fn get_velocity_detail(&self) -> Result<VelocityDetail> {
+        let detail_cmd = codec::encode_command(&self.prefix, b"VD", None);
+        let res = self.write(&detail_cmd).await?;
         check_reply(&res)?;
-        Ok((ascii_to_int(res.as_slice()) as f64) / (self.scale as f64))
+        let mut fields = codec::parse_fields::<isize>(&res, Layout::Delimited(b','))?
+            .into_iter()
+            .map(|field| field.map(|counts| counts as f64 / self.signed_scale()));
+        Ok(VelocityDetail {
+            commanded: fields.next().flatten(),
+            actual: fields.next().flatten(),
+        })
+    }
+
+    /// Where the current move is in its velocity profile. The firmware does not report this, so
+    /// it is derived from two `get_velocity_detail` samples 20 ms apart: within 2 % of the
+    /// commanded velocity is cruising, otherwise the change in speed between the samples tells
+    /// accelerating from decelerating. Very short ramps can be missed between samples.
+    pub async fn motion_phase(&self) -> Result<MotionPhase> {
+        if self.get_status().await? != Status::Moving {
+            return Ok(MotionPhase::Idle);
+        }
+        let first = self.get_velocity_detail().await?;
+        tokio::time::sleep(PHASE_SAMPLE_GAP).await;
+        let second = self.get_velocity_detail().await?;
+        let (Some(before), Some(now)) = (first.actual, second.actual) else {
+            return Err(anyhow!("firmware does not report actual velocity"));
+        };
+        let commanded = second.commanded.or(self.state().velocity);
+        match commanded {
+            Some(commanded)
+                if (now.abs() - commanded.abs()).abs() <= commanded.abs() * CRUISE_TOLERANCE =>
+            {
+                Ok(MotionPhase::Cruising)
+            }
+            _ if now.abs() > before.abs() => Ok(MotionPhase::Accelerating),
+            _ if now.abs() < before.abs() => Ok(MotionPhase::Decelerating),
+            _ => Ok(MotionPhase::Cruising),
+        }
     }
 
     pub async fn clear_alerts(&self) -> Result<()> {
-        let clear_cmd = [2, b'M', self.id + 48, b'C', b'A', 13];
-        let resp = self.write(clear_cmd.as_slice()).await;
+        let clear_cmd = codec::encode_clear_alerts(&self.prefix);
+        let resp = self.write(&clear_cmd).await?;
+        check_reply(&resp)?;
+        Ok(())
+    }
+
+    /// Runs the firmware homing routine (`M<id>HM`), polls status until it finishes, then zeroes
+    /// the position and clears `position_lost`. Fails if the motor faults while homing; if the
+    /// routine is not done within `timeout`, e.g. because a limit switch is miswired, the motor
+    /// is stopped and the call fails with `ClearCoreError::Timeout`.
+    pub async fn home(&self, timeout: Duration) -> Result<()> {
+        let home_cmd = codec::encode_home(&self.prefix);
+        self.expect_motion();
+        let resp = self.write(&home_cmd).await?;
         check_reply(&resp)?;
+        let homing = async {
+            let mut tick_interval = tokio::time::interval(Duration::from_millis(250));
+            tick_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+            loop {
+                match self.get_status().await? {
+                    Status::Moving => tick_interval.tick().await,
+                    Status::Faulted => {
+                        return Err(ClearCoreError::Faulted)
+                            .with_context(|| format!("motor {} homing", self.id));
+                    }
+                    _ => return Ok(()),
+                };
+            }
+        };
+        match tokio::time::timeout(timeout, homing).await {
+            Ok(result) => result?,
+            Err(_) => {
+                if let Err(e) = self.stop().await {
+                    error!(
+                        "Failed to stop motor {} after homing timed out: {e}",
+                        self.id
+                    );
+                }
+                return Err(ClearCoreError::Timeout(timeout).into());
+            }
+        }
+        self.set_position(0).await?;
+        self.position_lost.store(false, Ordering::SeqCst);
         Ok(())
     }
 
@@ -208,8 +1231,376 @@ impl ClearCoreMotor {
         let mut tick_interval = tokio::time::interval(interval);
         tick_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
         while self.get_status().await? == Status::Moving {
+            if self.state().peak_tracking {
+                let detail = self.get_velocity_detail().await?;
+                let torque = self.get_torque().await?;
+                self.state().peaks.record(detail, torque);
+            }
             tick_interval.tick().await;
         }
         Ok(())
     }
+
+    /// Makes `wait_for_move` also sample velocities and torque for `peak_stats`, at the cost of
+    /// two extra queries per poll.
+    pub fn set_peak_tracking(&self, enabled: bool) {
+        self.state().peak_tracking = enabled;
+    }
+
+    pub fn peak_stats(&self) -> PeakStats {
+        self.state().peaks
+    }
+
+    pub fn reset_peak_stats(&self) {
+        self.state().peaks = PeakStats::default();
+    }
+
+    /// Moves to `position` and waits for the move to finish, polling status every `poll`, then
+    /// for the settle delay if the move completed. A fault ends the wait at the next poll with
+    /// `ClearCoreError::Faulted`, the alerts in its context, rather than `MoveOutcome::Faulted`.
+    ///
+    /// Cancellation: if the returned future is dropped after the controller accepted the move but
+    /// before the move finished (e.g. it lost a `select!` or its task was aborted), or waiting
+    /// for the move errors, a `stop` is issued from a spawned task. Only this motor is affected.
+    pub async fn absolute_move_blocking(
+        &self,
+        position: impl IntoCounts,
+        poll: Duration,
+    ) -> Result<MoveOutcome> {
+        self.move_blocking(self.absolute_move(position), poll).await
+    }
+
+    /// Moves by `distance` and waits like `absolute_move_blocking`.
+    pub async fn relative_move_blocking(
+        &self,
+        distance: impl IntoCounts,
+        poll: Duration,
+    ) -> Result<MoveOutcome> {
+        self.move_blocking(self.relative_move(distance), poll).await
+    }
+
+    async fn move_blocking(
+        &self,
+        start: impl Future<Output = Result<()>>,
+        poll: Duration,
+    ) -> Result<MoveOutcome> {
+        let stops = self.state().stops;
+        start.await?;
+        let guard = StopGuard::arm(self);
+        let outcome = self
+            .move_outcome(self.wait_for_move(poll), stops, None)
+            .await?;
+        if let MoveOutcome::Faulted(alerts) = &outcome {
+            return Err(ClearCoreError::Faulted).with_context(|| {
+                format!("motor {} faulted during move, alerts {alerts:?}", self.id)
+            });
+        }
+        guard.disarm();
+        if outcome == MoveOutcome::Completed {
+            tokio::time::sleep(self.settle_delay).await;
+        }
+        Ok(outcome)
+    }
+
+    /// Visits `points` in order, waiting for each move to finish and then dwelling for `dwell`.
+    /// Stops at the first move that does not complete, naming its index. Dropping the future
+    /// stops the motor, as with `absolute_move_blocking`.
+    pub async fn move_waypoints(&self, points: &[f64], dwell: Duration) -> Result<()> {
+        for (idx, &point) in points.iter().enumerate() {
+            let outcome = self
+                .absolute_move_blocking(point, WAYPOINT_POLL)
+                .await
+                .with_context(|| format!("waypoint {idx} ({point})"))?;
+            if outcome != MoveOutcome::Completed {
+                return Err(anyhow!("waypoint {idx} ({point}) ended with {outcome:?}"));
+            }
+            tokio::time::sleep(dwell).await;
+        }
+        Ok(())
+    }
+
+    /// Progress of the last move commanded through this client, with the position read from
+    /// the controller. The start, target and timing are tracked client-side, so a move started
+    /// by another client is not seen.
+    pub async fn move_progress(&self) -> Result<MoveProgress> {
+        let commanded = self
+            .state()
+            .commanded_move
+            .ok_or_else(|| anyhow!("no move commanded on motor {}", self.id))?;
+        let position = self.get_position().await?;
+        let elapsed = commanded.at.elapsed();
+        let distance = commanded.target - commanded.start;
+        let fraction = if distance == 0. {
+            1.
+        } else {
+            ((position - commanded.start) / distance).clamp(0., 1.)
+        };
+        let remaining = commanded
+            .duration()
+            .map(|total| Duration::from_secs_f64(total).saturating_sub(elapsed));
+        Ok(MoveProgress {
+            elapsed,
+            position,
+            start: commanded.start,
+            target: commanded.target,
+            fraction,
+            remaining,
+        })
+    }
+
+    /// Registers `cb` to run with the outcome of every move on this motor (and its clones) once
+    /// the crate sees it finish, i.e. from `wait_for_move_outcome`, `absolute_move_blocking` and
+    /// the helpers built on them. Each move is reported once; a timed-out wait reports nothing.
+    /// The callback runs on the waiting task and must not register further callbacks.
+    pub fn on_move_complete(&self, cb: impl Fn(MoveOutcome) + Send + 'static) {
+        self.move_callbacks
+            .lock()
+            .expect("move callbacks poisoned")
+            .push(Box::new(cb));
+    }
+
+    fn report_move_complete(&self, outcome: &MoveOutcome) {
+        if !self.state().take_unreported_move() {
+            return;
+        }
+        for cb in self
+            .move_callbacks
+            .lock()
+            .expect("move callbacks poisoned")
+            .iter()
+        {
+            cb(outcome.clone());
+        }
+    }
+
+    /// Waits for the current move like `wait_for_move` and reports how it ended. Limit
+    /// and fault information comes from the alert register, which stays latched until
+    /// `clear_alerts`.
+    pub async fn wait_for_move_outcome(
+        &self,
+        poll: Duration,
+        timeout: Option<Duration>,
+    ) -> Result<MoveOutcome> {
+        let stops = self.state().stops;
+        self.move_outcome(self.wait_for_move(poll), stops, timeout)
+            .await
+    }
+
+    async fn move_outcome(
+        &self,
+        wait: impl Future<Output = Result<()>>,
+        stops: u64,
+        timeout: Option<Duration>,
+    ) -> Result<MoveOutcome> {
+        match timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, wait).await {
+                Ok(res) => res?,
+                Err(_) => return Ok(MoveOutcome::Timeout),
+            },
+            None => wait.await?,
+        }
+        let alerts = self.get_alert_register().await?;
+        let outcome = if self.get_status().await? == Status::Faulted {
+            MoveOutcome::Faulted(AlertKind::decode(alerts))
+        } else if alerts & ALERT_POSITIVE_LIMIT != 0 {
+            MoveOutcome::LimitHit(Limit::Positive)
+        } else if alerts & ALERT_NEGATIVE_LIMIT != 0 {
+            MoveOutcome::LimitHit(Limit::Negative)
+        } else if self.state().stops != stops {
+            MoveOutcome::Stopped
+        } else {
+            self.check_clamped().await?
+        };
+        self.report_move_complete(&outcome);
+        Ok(outcome)
+    }
+
+    /// Compares where a completed move ended with its target when `set_clamp_check` is on.
+    async fn check_clamped(&self) -> Result<MoveOutcome> {
+        let (tolerance, requested) = {
+            let state = self.state();
+            (
+                state.clamp_tolerance,
+                state.commanded_move.map(|m| m.target),
+            )
+        };
+        let (Some(tolerance), Some(requested)) = (tolerance, requested) else {
+            return Ok(MoveOutcome::Completed);
+        };
+        if !requested.is_finite() {
+            return Ok(MoveOutcome::Completed);
+        }
+        let actual = self.get_position().await?;
+        if (actual - requested).abs() > tolerance {
+            Ok(MoveOutcome::Clamped { requested, actual })
+        } else {
+            Ok(MoveOutcome::Completed)
+        }
+    }
+
+    /// Makes move outcomes read back the final position and report `MoveOutcome::Clamped` when
+    /// it is more than `tolerance` user units from the target; `None` turns the check off.
+    pub fn set_clamp_check(&self, tolerance: Option<f64>) {
+        self.state().clamp_tolerance = tolerance;
+    }
+
+    /// Raw ClearCore alert register (`M<id>AL`).
+    pub async fn get_alert_register(&self) -> Result<u32> {
+        let alerts_cmd = codec::encode_get_alerts(&self.prefix);
+        let res = self.write(&alerts_cmd).await?;
+        Ok(reply_int(&res)? as u32)
+    }
+
+    /// Active alerts, decoded from the alert register; empty when there are none. Alerts stay
+    /// latched until `clear_alerts`.
+    pub async fn get_alerts(&self) -> Result<Vec<AlertKind>> {
+        Ok(AlertKind::decode(self.get_alert_register().await?))
+    }
+
+    /// Reads the motor's HLFB input via `M<id>GH`; the reply is `1` while HLFB is asserted.
+    pub async fn get_hlfb(&self) -> Result<bool> {
+        let hlfb_cmd = codec::encode_command(&self.prefix, b"GH", None);
+        let res = self.write(&hlfb_cmd).await?;
+        Ok(reply_int(&res)? == 1)
+    }
+
+    /// HLFB mode the controller reads the motor's HLFB line in (`M<id>GM`), needed to make sense
+    /// of `get_hlfb`.
+    pub async fn hlfb_mode(&self) -> Result<HlfbMode> {
+        let mode_cmd = codec::encode_command(&self.prefix, b"GM", None);
+        let res = self.write(&mode_cmd).await?;
+        match reply_int(&res)? {
+            0 => Ok(HlfbMode::Static),
+            1 => Ok(HlfbMode::HasPwm),
+            2 => Ok(HlfbMode::HasBipolarPwm),
+            mode => Err(ClearCoreError::BadReply(res)).context(format!("unknown HLFB mode {mode}")),
+        }
+    }
+
+    /// Changes the HLFB mode (`M<id>SM<mode>`). The motor must be disabled so the drive is not
+    /// being monitored through the old mode while it changes.
+    pub async fn set_hlfb_mode(&self, mode: HlfbMode) -> Result<()> {
+        if self.get_status().await? != Status::Disabled {
+            return Err(anyhow!(
+                "motor {} must be disabled to change its HLFB mode",
+                self.id
+            ));
+        }
+        let msg = codec::encode_command(&self.prefix, b"SM", Some(mode.code()));
+        let resp = self.write(msg.as_slice()).await?;
+        check_reply(&resp)?;
+        Ok(())
+    }
+
+    /// Waits until the move has finished and HLFB (`M<id>GH`) is asserted. With the drive's HLFB
+    /// configured for "In Range" / "ASG" output this means the servo has settled inside its
+    /// position window, rather than merely that the controller stopped commanding motion. Fails
+    /// with `ClearCoreError::Timeout` if `timeout` elapses first.
+    pub async fn wait_for_in_position(&self, timeout: Duration) -> Result<()> {
+        tokio::time::timeout(timeout, async {
+            let mut tick_interval = tokio::time::interval(IN_POSITION_POLL);
+            tick_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+            loop {
+                tick_interval.tick().await;
+                match self.get_status().await? {
+                    Status::Faulted => return Err(ClearCoreError::Faulted.into()),
+                    Status::Moving => continue,
+                    _ => {
+                        if self.get_hlfb().await? {
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+        })
+        .await
+        .map_err(|_| ClearCoreError::Timeout(timeout))
+        .with_context(|| format!("motor {} did not settle in position", self.id))?
+    }
+
+    /// Polls the position every `poll` until it is within `tolerance` of `target`, which unlike
+    /// `wait_for_move` catches a motor that stopped short. Fails if the motor faults, or with
+    /// `ClearCoreError::Timeout` naming the last position read if `timeout` elapses first.
+    pub async fn wait_for_position(
+        &self,
+        target: f64,
+        tolerance: f64,
+        poll: Duration,
+        timeout: Duration,
+    ) -> Result<()> {
+        let mut last = None;
+        let arrival = async {
+            let mut tick_interval = tokio::time::interval(poll);
+            tick_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+            loop {
+                tick_interval.tick().await;
+                if self.get_status().await? == Status::Faulted {
+                    return Err(ClearCoreError::Faulted)
+                        .with_context(|| format!("motor {} moving to {target}", self.id));
+                }
+                let position = self.get_position().await?;
+                if (position - target).abs() <= tolerance {
+                    return Ok(());
+                }
+                last = Some(position);
+            }
+        };
+        match tokio::time::timeout(timeout, arrival).await {
+            Ok(result) => result,
+            Err(_) => {
+                let position = last.map_or("unknown".to_string(), |p| p.to_string());
+                Err(ClearCoreError::Timeout(timeout)).with_context(|| {
+                    format!(
+                        "motor {} at {position}, not within {tolerance} of {target}",
+                        self.id
+                    )
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::{CR, STX};
+    use crate::error::ControllerErrorCode;
+
+    fn bad_reply(result: Result<Status>) -> Option<ClearCoreError> {
+        result.err()?.downcast_ref::<ClearCoreError>().cloned()
+    }
+
+    #[test]
+    fn parse_status_rejects_short_replies() {
+        for reply in [&[][..], &[STX, CR], &[STX, b'M', b'0']] {
+            assert_eq!(
+                bad_reply(parse_status(reply)),
+                Some(ClearCoreError::BadReply(reply.to_vec()))
+            );
+        }
+    }
+
+    #[test]
+    fn parse_status_codes() {
+        assert_eq!(parse_status(b"\x02M03\r").unwrap(), Status::Ready);
+        assert_eq!(parse_status(b"\x02M04\r").unwrap(), Status::Moving);
+        assert_eq!(
+            bad_reply(parse_status(b"\x02M09\r")),
+            Some(ClearCoreError::UnknownStatus(b'9'))
+        );
+        assert!(matches!(
+            bad_reply(parse_status(b"\x02M0?5\r")),
+            Some(ClearCoreError::Rejected {
+                code: ControllerErrorCode::Busy,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn stop_guard_dropped_outside_a_runtime_does_not_panic() {
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        let motor = ClearCoreMotor::new(0, 800, tx);
+        drop(StopGuard::arm(&motor));
+    }
 }
@@ -1,25 +1,144 @@
-use crate::controller::Message;
-use log::{debug, error};
+use crate::codec::{CommandKind, describe_frame, extension};
+use crate::controller::{Message, Priority, rejection_code};
+use crate::error::{ClearCoreError, ControllerErrorCode};
+use anyhow::Result;
+use log::{debug, error, trace};
 use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+
+tokio::task_local! {
+    static COMMAND_CONTEXT: Arc<str>;
+    static CRITICAL_SECTION: mpsc::Sender<Message>;
+    static COMMAND_PRIORITY: Priority;
+}
+
+/// Context set by the innermost enclosing `with_context` in the current task.
+pub(crate) fn current_context() -> Option<Arc<str>> {
+    COMMAND_CONTEXT.try_with(Arc::clone).ok()
+}
+
+pub(crate) async fn scope_context<F: Future>(context: Option<Arc<str>>, f: F) -> F::Output {
+    match context {
+        Some(context) => COMMAND_CONTEXT.scope(context, f).await,
+        None => f.await,
+    }
+}
+
+pub(crate) async fn scope_priority<F: Future>(priority: Option<Priority>, f: F) -> F::Output {
+    match priority {
+        Some(priority) => COMMAND_PRIORITY.scope(priority, f).await,
+        None => f.await,
+    }
+}
+
+pub(crate) async fn scope_critical<F: Future>(section: mpsc::Sender<Message>, f: F) -> F::Output {
+    CRITICAL_SECTION.scope(section, f).await
+}
+
+/// Task-locals commands pick up from the task sending them, captured so helpers that spawn
+/// tasks can carry them over.
+#[derive(Clone)]
+pub(crate) struct TaskScope {
+    context: Option<Arc<str>>,
+    priority: Option<Priority>,
+    critical_section: Option<mpsc::Sender<Message>>,
+}
+
+impl TaskScope {
+    pub fn current() -> Self {
+        Self {
+            context: current_context(),
+            priority: COMMAND_PRIORITY.try_with(|priority| *priority).ok(),
+            critical_section: CRITICAL_SECTION.try_with(Clone::clone).ok(),
+        }
+    }
+
+    pub async fn run<F: Future>(self, f: F) -> F::Output {
+        let f = scope_priority(self.priority, scope_context(self.context, f));
+        match self.critical_section {
+            Some(section) => scope_critical(section, f).await,
+            None => f.await,
+        }
+    }
+}
+
+/// Upper bound `write` puts on queueing plus the reply, well above the client's per-kind reply
+/// timeouts so those normally fire first.
+const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub(crate) trait SendRecv {
     fn get_sender(&self) -> &mpsc::Sender<Message>;
-    fn write(&self, buffer: &[u8]) -> impl Future<Output = Vec<u8>>
+    fn write(&self, buffer: &[u8]) -> impl Future<Output = Result<Vec<u8>>>
     where
         Self: Sync,
     {
-        async {
+        self.write_timeout(buffer, DEFAULT_WRITE_TIMEOUT)
+    }
+
+    /// Sends `buffer` and waits at most `timeout` for its reply, including time spent queued
+    /// behind other commands.
+    fn write_timeout(
+        &self,
+        buffer: &[u8],
+        timeout: Duration,
+    ) -> impl Future<Output = Result<Vec<u8>>>
+    where
+        Self: Sync,
+    {
+        async move {
             let (resp_tx, resp_rx) = oneshot::channel();
+            let priority = match CommandKind::of(buffer) {
+                CommandKind::Stop => Priority::STOP,
+                _ => COMMAND_PRIORITY
+                    .try_with(|priority| *priority)
+                    .unwrap_or(Priority::NORMAL),
+            };
             let msg = Message {
                 buffer: buffer.to_vec(),
                 response: resp_tx,
+                context: current_context(),
+                priority,
+            };
+            debug!(
+                "Sending {} at {:?}{}",
+                describe_frame(buffer),
+                priority,
+                msg.context
+                    .as_ref()
+                    .map(|context| format!(" (context: {context})"))
+                    .unwrap_or_default()
+            );
+            let section = CRITICAL_SECTION.try_with(Clone::clone).ok();
+            let sender = section.as_ref().unwrap_or(self.get_sender());
+            let sent = Instant::now();
+            let exchange = async {
+                // Without the client nothing will ever answer, so fail now rather than wait out
+                // the timeout.
+                if sender.send(msg).await.is_err() {
+                    error!("Send error: client task has stopped");
+                    return Err(ClearCoreError::Disconnected.into());
+                }
+                let reply = resp_rx
+                    .await
+                    .map_err(|_| ClearCoreError::ConnectionLost)??;
+                if let Some(extension) = extension(buffer)
+                    && rejection_code(&reply) == Some(ControllerErrorCode::UnknownCommand)
+                {
+                    return Err(ClearCoreError::Unsupported(extension).into());
+                }
+                trace!(
+                    "{} answered in {:?}",
+                    describe_frame(buffer),
+                    sent.elapsed()
+                );
+                Ok(reply)
             };
-            debug!("Sending msg: {:?}", msg);
-            if let Err(e) = self.get_sender().send(msg).await {
-                error!("Send error: {:?}", e);
-            }
-            resp_rx.await.expect("No MSG from client")
+            tokio::time::timeout(timeout, exchange)
+                .await
+                .map_err(|_| ClearCoreError::Timeout(timeout))?
         }
     }
 }
@@ -0,0 +1,205 @@
+use tokio::time::Instant;
+
+use crate::motor::PeakStats;
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Reading {
+    pub position: f64,
+    pub at: Instant,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct CommandedMove {
+    pub start: f64,
+    pub target: f64,
+    pub velocity: f64,
+    pub acceleration: Option<f64>,
+    pub deceleration: Option<f64>,
+    pub at: Instant,
+}
+
+/// Trapezoidal velocity profile of a move: ramp up at `accel` to `peak`, cruise, ramp down.
+struct Profile {
+    accel: f64,
+    decel: f64,
+    peak: f64,
+    t_accel: f64,
+    t_cruise: f64,
+    t_decel: f64,
+}
+
+impl CommandedMove {
+    /// The move's profile, or `None` for a move that goes nowhere. An unknown acceleration or
+    /// deceleration is treated as instantaneous.
+    fn profile(&self) -> Option<Profile> {
+        let distance = (self.target - self.start).abs();
+        if self.velocity <= 0. || distance == 0. {
+            return None;
+        }
+        let accel = self
+            .acceleration
+            .filter(|a| *a > 0.)
+            .unwrap_or(f64::INFINITY);
+        let decel = self
+            .deceleration
+            .filter(|d| *d > 0.)
+            .unwrap_or(f64::INFINITY);
+        let mut peak = self.velocity;
+        if peak * peak / (2. * accel) + peak * peak / (2. * decel) > distance {
+            peak = (2. * distance * accel * decel / (accel + decel)).sqrt();
+        }
+        let (t_accel, t_decel) = (peak / accel, peak / decel);
+        let (s_accel, s_decel) = (peak * t_accel / 2., peak * t_decel / 2.);
+        Some(Profile {
+            accel,
+            decel,
+            peak,
+            t_accel,
+            t_cruise: (distance - s_accel - s_decel) / peak,
+            t_decel,
+        })
+    }
+
+    /// Distance covered `elapsed` seconds into the move assuming a trapezoidal profile.
+    fn travelled(&self, elapsed: f64) -> f64 {
+        let Some(Profile {
+            accel,
+            decel,
+            peak,
+            t_accel,
+            t_cruise,
+            t_decel,
+        }) = self.profile()
+        else {
+            return 0.;
+        };
+        let s_accel = peak * t_accel / 2.;
+        if elapsed < t_accel {
+            accel * elapsed * elapsed / 2.
+        } else if elapsed < t_accel + t_cruise {
+            s_accel + peak * (elapsed - t_accel)
+        } else if elapsed < t_accel + t_cruise + t_decel {
+            let t = elapsed - t_accel - t_cruise;
+            s_accel + peak * t_cruise + peak * t - decel * t * t / 2.
+        } else {
+            (self.target - self.start).abs()
+        }
+    }
+
+    /// Expected duration of the whole move in seconds; `None` if it never ends (a jog) or the
+    /// velocity is unknown.
+    pub fn duration(&self) -> Option<f64> {
+        if self.target == self.start {
+            return Some(0.);
+        }
+        self.profile()
+            .map(|profile| profile.t_accel + profile.t_cruise + profile.t_decel)
+            .filter(|duration| duration.is_finite())
+    }
+
+    fn position_at(&self, now: Instant) -> f64 {
+        let travelled = self.travelled(now.duration_since(self.at).as_secs_f64());
+        self.start + travelled.copysign(self.target - self.start)
+    }
+}
+
+/// Client-side record of what has been commanded to and read back from a motor, shared by all
+/// clones of a `ClearCoreMotor`.
+#[derive(Debug, Default)]
+pub(crate) struct MotionState {
+    pub last_reading: Option<Reading>,
+    pub commanded_move: Option<CommandedMove>,
+    pub velocity: Option<f64>,
+    pub acceleration: Option<f64>,
+    pub deceleration: Option<f64>,
+    /// Speed of the jog in progress, see `commanded_jog_speed`.
+    pub jog_speed: Option<f64>,
+    /// Number of `stop`/`abrupt_stop` commands accepted, to tell a stopped move from one that ran
+    /// to completion.
+    pub stops: u64,
+    /// Moves commanded so far and the count at the last reported completion, so each move is
+    /// reported to `on_move_complete` callbacks once.
+    pub moves: u64,
+    pub reported_moves: u64,
+    /// When the last enable or disable was accepted, see `with_min_cycle_interval`.
+    pub last_transition: Option<Instant>,
+    /// Tolerance for `MoveOutcome::Clamped`, see `set_clamp_check`.
+    pub clamp_tolerance: Option<f64>,
+    pub peak_tracking: bool,
+    pub peaks: PeakStats,
+    /// Commands that can start or continue motion sent so far, and how many of those a status
+    /// read has since seen the motor at rest after, see `set_unexpected_motion_check`.
+    pub motion_commands: u64,
+    pub settled_commands: u64,
+    pub motion_check: bool,
+}
+
+impl MotionState {
+    pub fn estimate(&self, now: Instant) -> Option<f64> {
+        match (self.last_reading, self.commanded_move) {
+            (Some(reading), Some(commanded)) if reading.at > commanded.at => {
+                let remaining = commanded.target - reading.position;
+                let elapsed = now.duration_since(reading.at).as_secs_f64();
+                let travelled = (commanded.velocity * elapsed).min(remaining.abs());
+                Some(reading.position + travelled.copysign(remaining))
+            }
+            (_, Some(commanded)) => Some(commanded.position_at(now)),
+            (Some(reading), None) => Some(reading.position),
+            (None, None) => None,
+        }
+    }
+
+    pub fn record_reading(&mut self, position: f64) {
+        self.last_reading = Some(Reading {
+            position,
+            at: Instant::now(),
+        });
+    }
+
+    pub fn record_move(&mut self, target: f64, velocity: f64) {
+        self.moves += 1;
+        self.jog_speed = None;
+        let now = Instant::now();
+        let start = self.estimate(now).unwrap_or(target);
+        self.commanded_move = Some(CommandedMove {
+            start,
+            target,
+            velocity,
+            acceleration: self.acceleration,
+            deceleration: self.deceleration,
+            at: now,
+        });
+    }
+
+    /// Whether the latest commanded move still has to be reported as complete.
+    pub fn take_unreported_move(&mut self) -> bool {
+        let unreported = self.reported_moves != self.moves;
+        self.reported_moves = self.moves;
+        unreported
+    }
+
+    /// Records a status read sent when `commands` motion commands had been sent. Returns whether
+    /// the check is on and the motor was moving with all of them already seen settled.
+    pub fn observe_status(&mut self, moving: bool, commands: u64) -> bool {
+        if !moving {
+            self.settled_commands = self.settled_commands.max(commands);
+            return false;
+        }
+        self.motion_check && self.settled_commands == self.motion_commands
+    }
+
+    /// Freezes the estimate where it currently is; the motor is no longer heading anywhere.
+    pub fn record_stop(&mut self) {
+        self.stops += 1;
+        self.jog_speed = None;
+        let now = Instant::now();
+        self.commanded_move = self.estimate(now).map(|position| CommandedMove {
+            start: position,
+            target: position,
+            velocity: 0.,
+            acceleration: None,
+            deceleration: None,
+            at: now,
+        });
+    }
+}